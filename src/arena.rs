@@ -0,0 +1,460 @@
+use std::{collections::HashMap, fmt::Display, sync::{atomic::{AtomicUsize, Ordering}, Mutex}, thread, time::Duration};
+
+use cozy_chess_types::Color;
+
+use crate::{
+    chess::ChessState,
+    engine::{adjudication::{AdjudicationConfig, Adjudicator}, selfplay::GameOutcome, time::TimeManager, tools::{AccumulativeAnalysis, Tools}},
+};
+
+#[derive(Debug, Clone)]
+pub struct SearchSettings {
+    pub move_time: Duration,
+    pub c: f32,
+}
+
+impl Default for SearchSettings {
+    fn default() -> Self {
+        Self { move_time: Duration::from_millis(500), c: 2.0 }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArenaConfig {
+    pub games: usize,
+    pub threads: usize,
+    /// The candidate is promoted once the lower end of the 95% confidence interval on its score against the
+    /// current best reaches this...
+    pub threshold: f32,
+    /// ...or once its likelihood of superiority does.
+    pub min_los: f32,
+    pub search: SearchSettings,
+    pub adjudication: AdjudicationConfig,
+}
+
+impl Default for ArenaConfig {
+    fn default() -> Self {
+        Self {
+            games: 40,
+            threads: 4,
+            threshold: 0.55,
+            min_los: 0.95,
+            search: SearchSettings::default(),
+            adjudication: AdjudicationConfig::default(),
+        }
+    }
+}
+
+impl ArenaConfig {
+    /// Whether a match with these results is clear enough to promote the candidate, so a lucky short match
+    /// doesn't replace the best network with an equal or weaker one.
+    pub fn promotes(&self, stats: &MatchStats) -> bool {
+        stats.score_bounds().0 >= self.threshold || stats.los() >= self.min_los
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchConfig {
+    pub games: usize,
+    pub threads: usize,
+    pub adjudication: AdjudicationConfig,
+}
+
+/// Game results from the first player's point of view.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MatchStats {
+    pub wins: usize,
+    pub draws: usize,
+    pub losses: usize,
+}
+
+impl MatchStats {
+    pub fn games(&self) -> usize {
+        self.wins + self.draws + self.losses
+    }
+
+    pub fn add(&mut self, score: f32) {
+        if score > 0.5 { self.wins += 1 }
+        else if score < 0.5 { self.losses += 1 }
+        else { self.draws += 1 }
+    }
+
+    pub fn score(&self) -> f32 {
+        if self.games() == 0 { return 0.5 }
+        (self.wins as f32 + 0.5 * self.draws as f32) / self.games() as f32
+    }
+
+    /// Standard error of the per-game score.
+    fn score_error(&self) -> f32 {
+        let n = self.games() as f32;
+        if n == 0.0 { return 0.0 }
+        let s = self.score();
+        let var = (self.wins as f32 * (1.0 - s).powi(2)
+            + self.draws as f32 * (0.5 - s).powi(2)
+            + self.losses as f32 * s.powi(2)) / n;
+        (var / n).sqrt()
+    }
+
+    /// The 95% confidence interval on `score()`.
+    pub fn score_bounds(&self) -> (f32, f32) {
+        let (s, e) = (self.score(), 1.96 * self.score_error());
+        (s - e, s + e)
+    }
+
+    pub fn elo(&self) -> f32 {
+        score_to_elo(self.score())
+    }
+
+    /// Half-width of the 95% confidence interval on `elo()`.
+    pub fn elo_error(&self) -> f32 {
+        let (lower, upper) = self.score_bounds();
+        (score_to_elo(upper) - score_to_elo(lower)) / 2.0
+    }
+
+    /// Likelihood of superiority: the probability that the first player is the stronger one.
+    pub fn los(&self) -> f32 {
+        let decisive = (self.wins + self.losses) as f32;
+        if decisive == 0.0 { return 0.5 }
+        0.5 * (1.0 + erf((self.wins as f32 - self.losses as f32) / (2.0 * decisive).sqrt()))
+    }
+}
+
+impl Display for MatchStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "+{} ={} -{} score {:.3} elo {:.1} +/- {:.1} los {:.1}%",
+            self.wins, self.draws, self.losses, self.score(), self.elo(), self.elo_error(), 100.0 * self.los(),
+        )
+    }
+}
+
+/// Sequential probability ratio test between H0: elo = `elo0` and H1: elo = `elo1`.
+#[derive(Debug, Clone, Copy)]
+pub struct Sprt {
+    pub elo0: f32,
+    pub elo1: f32,
+    pub alpha: f32,
+    pub beta: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SprtResult {
+    AcceptH0,
+    AcceptH1,
+    Continue,
+}
+
+impl Sprt {
+    /// Lower and upper log-likelihood ratio bounds at which the test stops.
+    pub fn bounds(&self) -> (f32, f32) {
+        ((self.beta / (1.0 - self.alpha)).ln(), ((1.0 - self.beta) / self.alpha).ln())
+    }
+
+    /// Log-likelihood ratio using the normal approximation to the trinomial score distribution.
+    pub fn llr(&self, stats: &MatchStats) -> f32 {
+        let n = stats.games() as f32;
+        if n == 0.0 { return 0.0 }
+
+        let s = stats.score();
+        let var = (stats.wins as f32 + stats.draws as f32 / 4.0) / n - s * s;
+        if var <= 0.0 { return 0.0 }
+
+        let (s0, s1) = (elo_to_score(self.elo0), elo_to_score(self.elo1));
+        (s1 - s0) * (2.0 * s - s0 - s1) / (2.0 * var / n)
+    }
+
+    pub fn test(&self, stats: &MatchStats) -> SprtResult {
+        let llr = self.llr(stats);
+        let (lower, upper) = self.bounds();
+        if llr <= lower { SprtResult::AcceptH0 }
+        else if llr >= upper { SprtResult::AcceptH1 }
+        else { SprtResult::Continue }
+    }
+}
+
+impl Default for Sprt {
+    fn default() -> Self {
+        Self { elo0: 0.0, elo1: 5.0, alpha: 0.05, beta: 0.05 }
+    }
+}
+
+pub fn elo_to_score(elo: f32) -> f32 {
+    1.0 / (1.0 + 10f32.powf(-elo / 400.0))
+}
+
+pub fn score_to_elo(score: f32) -> f32 {
+    let s = score.clamp(0.001, 0.999);
+    -400.0 * (1.0 / s - 1.0).log10()
+}
+
+/// Abramowitz & Stegun 7.1.26, accurate to ~1e-7.
+fn erf(x: f32) -> f32 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let y = 1.0 - t * (0.254_829_6 + t * (-0.284_496_72 + t * (1.421_413_8 + t * (-1.453_152 + t * 1.061_405_4)))) * (-x * x).exp();
+    y.copysign(x)
+}
+
+/// Plays one game without noise, each side keeping its own search tree.
+pub fn play_game<A: Tools, B: Tools>(
+    white: (&A, &SearchSettings),
+    black: (&B, &SearchSettings),
+    start: ChessState,
+    adjudication: &AdjudicationConfig,
+) -> GameOutcome {
+    let mut trees = [
+        AccumulativeAnalysis::from_position(start.clone()).unwrap(),
+        AccumulativeAnalysis::from_position(start.clone()).unwrap(),
+    ];
+    let mut state = start;
+    let mut repetitions = HashMap::new();
+    let mut adjudicator = Adjudicator::new(adjudication.clone(), true);
+    let mut ply = 0;
+
+    loop {
+        if let Some(outcome) = GameOutcome::from_state(&state) { return outcome }
+        if let Some(outcome) = adjudicator.check_position(&state, ply) { return outcome }
+
+        let hash = state.board.hash();
+        let seen = repetitions.entry(hash).or_insert(0);
+        *seen += 1;
+        if *seen >= 3 { return GameOutcome::Draw }
+
+        let side = state.board.side_to_move() as usize;
+        let tree = &mut trees[side];
+        if tree.try_get_analysis(&hash).is_none() {
+            *tree = AccumulativeAnalysis::from_position(state.clone()).unwrap();
+        }
+
+        let result = match state.board.side_to_move() {
+            Color::White => tree.search(hash, white.0, white.1.c, &TimeManager::fixed(white.1.move_time)),
+            Color::Black => tree.search(hash, black.0, black.1.c, &TimeManager::fixed(black.1.move_time)),
+        };
+
+        let Some(result) = result else { return GameOutcome::Draw };
+        if let Some(outcome) = adjudicator.update(&state, ply, result.score()) { return outcome }
+
+        let Some(mv) = result.best_move else { return GameOutcome::Draw };
+        state.board.play_unchecked(mv);
+        ply += 1;
+    }
+}
+
+/// Plays up to `config.games` games between `a` and `b` in parallel, alternating colours and cycling through
+/// `openings` (each opening is played once with each colour). Stops early once `stop` returns true.
+pub fn play_match<A, B>(
+    a: (&A, &SearchSettings),
+    b: (&B, &SearchSettings),
+    openings: &[ChessState],
+    config: &MatchConfig,
+    stop: impl Fn(&MatchStats) -> bool + Sync,
+    report: impl Fn(usize, &MatchStats) + Sync,
+) -> MatchStats
+where
+    A: Tools + Clone + Send,
+    B: Tools + Clone + Send,
+{
+    let next = AtomicUsize::new(0);
+    let stats = Mutex::new(MatchStats::default());
+
+    thread::scope(|scope| {
+        for _ in 0..config.threads.max(1) {
+            let (a_tools, b_tools) = (a.0.clone(), b.0.clone());
+            let (next, stats, stop, report) = (&next, &stats, &stop, &report);
+
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= config.games || stop(&stats.lock().unwrap()) { break }
+
+                let start = openings.get((i / 2) % openings.len().max(1)).cloned().unwrap_or_default();
+                let score = if i % 2 == 0 {
+                    play_game((&a_tools, a.1), (&b_tools, b.1), start, &config.adjudication).score()
+                } else {
+                    1.0 - play_game((&b_tools, b.1), (&a_tools, a.1), start, &config.adjudication).score()
+                };
+
+                let mut stats = stats.lock().unwrap();
+                stats.add(score);
+                report(i, &stats);
+            });
+        }
+    });
+
+    stats.into_inner().unwrap()
+}
+
+#[derive(Debug, Clone)]
+pub struct GateResult {
+    pub stats: MatchStats,
+    pub promoted: bool,
+}
+
+pub struct Arena {
+    config: ArenaConfig,
+    openings: Vec<ChessState>,
+}
+
+impl Arena {
+    pub fn new(config: ArenaConfig) -> Self {
+        Self { config, openings: vec![] }
+    }
+
+    pub fn with_openings(mut self, openings: Vec<ChessState>) -> Self {
+        self.openings = openings;
+        self
+    }
+
+    /// Plays `candidate` against `best` and promotes it if the result clears the bar, see `ArenaConfig::promotes`.
+    pub fn gate<T: Tools + Clone + Send>(&self, candidate: &T, best: &T) -> GateResult {
+        let search = &self.config.search;
+        let stats = play_match(
+            (candidate, search),
+            (best, search),
+            &self.openings,
+            &MatchConfig {
+                games: self.config.games,
+                threads: self.config.threads,
+                adjudication: self.config.adjudication.clone(),
+            },
+            |_| false,
+            |_, _| (),
+        );
+
+        GateResult { promoted: self.config.promotes(&stats), stats }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::tools::RandTool;
+
+    use super::*;
+
+    fn stats(wins: usize, draws: usize, losses: usize) -> MatchStats {
+        MatchStats { wins, draws, losses }
+    }
+
+    fn close(a: f32, b: f32, tolerance: f32) -> bool {
+        (a - b).abs() < tolerance
+    }
+
+    #[test]
+    fn erf_matches_reference_values() {
+        assert!(close(erf(0.0), 0.0, 1e-6));
+        assert!(close(erf(0.5), 0.520_499_9, 1e-6));
+        assert!(close(erf(1.0), 0.842_700_8, 1e-6));
+        assert!(close(erf(-1.0), -0.842_700_8, 1e-6));
+        assert!(close(erf(3.0), 0.999_977_9, 1e-6));
+    }
+
+    #[test]
+    fn elo_matches_reference_values() {
+        assert!(close(stats(1, 2, 1).elo(), 0.0, 1e-3));
+        // A 75% score is 400 * log10(3) elo.
+        assert!(close(stats(3, 0, 1).elo(), 190.848_5, 1e-2));
+        assert!(close(stats(1, 0, 3).elo(), -190.848_5, 1e-2));
+    }
+
+    #[test]
+    fn elo_error_matches_reference_values() {
+        // Score 0.5 with a per-game variance of 0.15 over 100 games.
+        assert!(close(stats(30, 40, 30).elo_error(), 53.159, 0.05));
+        assert!(stats(300, 400, 300).elo_error() < stats(30, 40, 30).elo_error());
+        assert_eq!(stats(0, 0, 0).elo_error(), 0.0);
+    }
+
+    #[test]
+    fn los_matches_reference_values() {
+        assert_eq!(stats(0, 10, 0).los(), 0.5);
+        assert!(close(stats(5, 3, 5).los(), 0.5, 1e-6));
+        // 0.5 * (1 + erf(5 / sqrt(30))).
+        assert!(close(stats(10, 7, 5).los(), 0.901_647, 1e-5));
+        assert!(close(stats(5, 7, 10).los(), 1.0 - 0.901_647, 1e-5));
+    }
+
+    #[test]
+    fn sprt_bounds_match_reference_values() {
+        let (lower, upper) = Sprt::default().bounds();
+        assert!(close(lower, -2.944_439, 1e-5));
+        assert!(close(upper, 2.944_439, 1e-5));
+
+        let (lower, upper) = Sprt { alpha: 0.05, beta: 0.1, ..Sprt::default() }.bounds();
+        assert!(close(lower, -2.251_292, 1e-5));
+        assert!(close(upper, 2.890_372, 1e-5));
+    }
+
+    #[test]
+    fn sprt_llr_matches_reference_values() {
+        // Normal approximation to the trinomial LLR, as in cutechess and fishtest, for elo0 = 0 and elo1 = 5.
+        let sprt = Sprt::default();
+        assert!(close(sprt.llr(&stats(1200, 1500, 1000)), 4.216_758, 1e-2));
+        assert!(close(sprt.llr(&stats(1000, 1500, 1200)), -5.511_703, 1e-2));
+        assert!(close(sprt.llr(&stats(100, 100, 100)), -0.046_592, 1e-3));
+        assert!(close(sprt.llr(&stats(2000, 3000, 1900)), 1.282_464, 1e-2));
+        assert_eq!(sprt.llr(&stats(0, 0, 0)), 0.0);
+        assert_eq!(sprt.llr(&stats(0, 10, 0)), 0.0);
+    }
+
+    #[test]
+    fn sprt_accepts_at_the_bounds() {
+        let sprt = Sprt::default();
+        assert_eq!(sprt.test(&stats(1200, 1500, 1000)), SprtResult::AcceptH1);
+        assert_eq!(sprt.test(&stats(1000, 1500, 1200)), SprtResult::AcceptH0);
+        assert_eq!(sprt.test(&stats(2000, 3000, 1900)), SprtResult::Continue);
+
+        // An LLR of 2.92 sits just below the default upper bound of 2.94, and above it with a looser alpha.
+        assert_eq!(sprt.test(&stats(3100, 3000, 2900)), SprtResult::Continue);
+        assert_eq!(Sprt { alpha: 0.06, ..sprt }.test(&stats(3100, 3000, 2900)), SprtResult::AcceptH1);
+        // The hypotheses aren't symmetric about zero, so the mirrored result is well past the lower bound.
+        assert_eq!(sprt.test(&stats(2900, 3000, 3100)), SprtResult::AcceptH0);
+    }
+
+    fn fast() -> SearchSettings {
+        SearchSettings { move_time: Duration::from_millis(2), ..SearchSettings::default() }
+    }
+
+    #[test]
+    fn finished_openings_are_scored_for_each_colour() {
+        let fools_mate = ChessState::from_fen("rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3").unwrap();
+        let stalemate = ChessState::from_fen("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1").unwrap();
+        let tools = RandTool::new(0);
+        let adjudication = AdjudicationConfig::none();
+
+        assert_eq!(play_game((&tools, &fast()), (&tools, &fast()), fools_mate.clone(), &adjudication), GameOutcome::BlackWin);
+        assert_eq!(play_game((&tools, &fast()), (&tools, &fast()), stalemate.clone(), &adjudication), GameOutcome::Draw);
+
+        // Each opening is played once with each colour, so `a` wins the fool's mate once as Black and loses it
+        // once as White.
+        let config = MatchConfig { games: 6, threads: 3, adjudication };
+        let stats = play_match((&tools, &fast()), (&tools, &fast()), &[fools_mate, stalemate], &config, |_| false, |_, _| ());
+        assert_eq!((stats.wins, stats.draws, stats.losses), (2, 2, 2));
+    }
+
+    #[test]
+    fn matches_play_out_every_game_and_can_stop_early() {
+        let config = MatchConfig {
+            games: 4,
+            threads: 2,
+            adjudication: AdjudicationConfig { max_plies: Some(4), ..AdjudicationConfig::none() },
+        };
+        let (a, b) = (RandTool::new(1), RandTool::new(2));
+
+        let stats = play_match((&a, &fast()), (&b, &fast()), &[], &config, |_| false, |_, _| ());
+        assert_eq!(stats.games(), 4);
+        assert_eq!(stats.draws, 4);
+
+        let stats = play_match((&a, &fast()), (&b, &fast()), &[], &config, |x| x.games() >= 1, |_, _| ());
+        assert!((1..4).contains(&stats.games()));
+    }
+
+    #[test]
+    fn gate_needs_a_clear_result() {
+        let config = ArenaConfig::default();
+        // Scores 0.6, but neither the lower bound (0.456) nor the LOS (0.909) clears the bar.
+        assert!(!config.promotes(&stats(22, 4, 14)));
+        // Lower bound 0.576 and LOS 0.998.
+        assert!(config.promotes(&stats(24, 8, 8)));
+        // Either criterion is enough on its own.
+        assert!(ArenaConfig { threshold: 1.0, ..config.clone() }.promotes(&stats(24, 8, 8)));
+        assert!(ArenaConfig { min_los: 1.0, ..config }.promotes(&stats(24, 8, 8)));
+    }
+}
@@ -1,7 +1,8 @@
-use cozy_chess::Board;
-use cozy_chess_types::{BitBoard, Color, Piece, Move};
+use cozy_chess::{Board, BoardBuilder};
+use cozy_chess_types::{BitBoard, Color, File, Piece, Move, Square};
 use ndarray::Array1;
 use std::{hash::Hash};
+use anyhow::Result;
 use crate::game::Game;
 
 
@@ -11,6 +12,14 @@ pub struct ChessState { // TODO: Clone maybe not needed
 }
 
 impl ChessState {
+    pub fn from_fen(fen: &str) -> Result<Self> {
+        Ok(Self { board: Board::from_fen(fen.trim(), false)? })
+    }
+
+    pub fn fen(&self) -> String {
+        self.board.to_string()
+    }
+
     pub fn moves(&self) -> Vec<Move> {
         let mut moves = vec![];
         self.board.generate_moves(|mvs| {
@@ -19,6 +28,68 @@ impl ChessState {
         });
         moves
     }
+
+    /// Number of half-moves played since the start of the game.
+    pub fn ply(&self) -> usize {
+        let moves = (self.board.fullmove_number() as usize).saturating_sub(1) * 2;
+        match self.board.side_to_move() {
+            Color::White => moves,
+            Color::Black => moves + 1,
+        }
+    }
+
+    /// True when neither side can checkmate: bare kings, a single minor piece, or only bishops all on one colour.
+    pub fn insufficient_material(&self) -> bool {
+        let board = &self.board;
+        let heavy = board.pieces(Piece::Pawn) | board.pieces(Piece::Rook) | board.pieces(Piece::Queen);
+        if !heavy.is_empty() { return false }
+
+        let knights = board.pieces(Piece::Knight);
+        let bishops = board.pieces(Piece::Bishop);
+        if (knights | bishops).len() <= 1 { return true }
+
+        knights.is_empty() && ((bishops & BitBoard::DARK_SQUARES).is_empty() || (bishops & BitBoard::LIGHT_SQUARES).is_empty())
+    }
+
+    /// The same position with colours swapped and ranks mirrored, so White's chances here are Black's there.
+    pub fn flipped(&self) -> Self {
+        let old = BoardBuilder::from_board(&self.board);
+        let mut new = BoardBuilder::from_board(&self.board);
+
+        for sq in Square::ALL {
+            *new.square_mut(sq.flip_rank()) = old.square(sq).map(|(piece, color)| (piece, !color));
+        }
+        new.side_to_move = !old.side_to_move;
+        new.castle_rights = [old.castle_rights[1], old.castle_rights[0]];
+        new.en_passant = old.en_passant.map(Square::flip_rank);
+
+        // Mirroring a legal position always gives a legal one.
+        Self { board: new.build().unwrap() }
+    }
+
+    /// The move in `flipped()` that mirrors `mv` in this position.
+    pub fn flip_move(mv: Move) -> Move {
+        Move { from: mv.from.flip_rank(), to: mv.to.flip_rank(), promotion: mv.promotion }
+    }
+
+    /// Formats a move in UCI notation, turning cozy-chess' king-takes-rook castling into e1g1 / e1c1.
+    pub fn uci(&self, mut mv: Move) -> String {
+        if self.board.color_on(mv.to) == Some(self.board.side_to_move()) {
+            let file = if mv.to.file() > mv.from.file() { File::G } else { File::C };
+            mv.to = Square::new(file, mv.from.rank());
+        }
+        mv.to_string()
+    }
+
+    /// Formats a line of moves played from this position in UCI notation.
+    pub fn uci_line(&self, moves: &[Move]) -> Vec<String> {
+        let mut board = self.clone();
+        moves.iter().map(|mv| {
+            let s = board.uci(*mv);
+            board.board.play_unchecked(*mv);
+            s
+        }).collect()
+    }
 }
 
 impl Hash for ChessState {
@@ -82,11 +153,65 @@ impl Game for ChessState {
 pub fn bitboard_to_array(board: &BitBoard) -> [f32; 64] {
     let mut state = [0.0; 64];
 
-    for i in 0..64 {
+    for (i, s) in state.iter_mut().enumerate() {
         if board.0 >> i & 1 == 1 {
-            state[i] = 1.0;
+            *s = 1.0;
         }
     }
 
     state
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flipping_mirrors_colours_and_ranks() {
+        let start = ChessState::default().flipped();
+        assert_eq!(start.fen(), "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b KQkq - 0 1");
+
+        let state = ChessState::from_fen("r3k2r/pp1n1ppp/8/2pP4/8/8/PPP2PPP/R3K2R w Kq c6 0 12").unwrap();
+        let flipped = state.flipped();
+        assert_eq!(flipped.fen(), "r3k2r/ppp2ppp/8/8/2Pp4/8/PP1N1PPP/R3K2R b Qk c3 0 12");
+        assert_eq!(flipped.flipped().board, state.board);
+
+        let flipped_moves = flipped.moves();
+        assert!(state.moves().into_iter().all(|mv| flipped_moves.contains(&ChessState::flip_move(mv))));
+    }
+
+    #[test]
+    fn insufficient_material() {
+        let insufficient = |fen| ChessState::from_fen(fen).unwrap().insufficient_material();
+
+        assert!(insufficient("4k3/8/8/8/8/8/8/4K3 w - - 0 1"));
+        assert!(insufficient("4k3/8/8/8/8/8/8/4KN2 w - - 0 1"));
+        assert!(insufficient("4k3/8/8/8/8/8/8/4KB2 b - - 0 1"));
+        assert!(insufficient("4kn2/8/8/8/8/8/8/4K3 w - - 0 1"));
+        // Bishops on dark squares only, then on both colours.
+        assert!(insufficient("4kb2/8/8/8/8/8/8/2B1K3 w - - 0 1"));
+        assert!(!insufficient("2b1k3/8/8/8/8/8/8/2B1K3 w - - 0 1"));
+        assert!(!insufficient("4k3/8/8/8/8/8/8/3NKN2 w - - 0 1"));
+        assert!(!insufficient("4kn2/8/8/8/8/8/8/2B1K3 w - - 0 1"));
+        // Any pawn, rook or queen can still mate.
+        assert!(!insufficient("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1"));
+        assert!(!insufficient("4k3/4p3/8/8/8/8/8/2B1K3 w - - 0 1"));
+        assert!(!insufficient("4k3/8/8/8/8/8/8/4KR2 w - - 0 1"));
+        assert!(!insufficient("4k3/8/8/8/8/8/8/4KQ2 w - - 0 1"));
+    }
+
+    #[test]
+    fn uci_formats_castling_as_king_moves() {
+        let white = ChessState::from_fen("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1").unwrap();
+        assert_eq!(white.uci("e1h1".parse().unwrap()), "e1g1");
+        assert_eq!(white.uci("e1a1".parse().unwrap()), "e1c1");
+        assert_eq!(white.uci("e1f1".parse().unwrap()), "e1f1");
+        assert_eq!(white.uci("a1a8".parse().unwrap()), "a1a8");
+
+        let black = ChessState::from_fen("r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1").unwrap();
+        assert_eq!(black.uci("e8h8".parse().unwrap()), "e8g8");
+        assert_eq!(black.uci("e8a8".parse().unwrap()), "e8c8");
+
+        let line = ["e1h1", "e8a8"].map(|x| x.parse().unwrap());
+        assert_eq!(white.uci_line(&line), ["e1g1", "e8c8"]);
+    }
+}
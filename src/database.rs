@@ -1,10 +1,13 @@
 use base64::{engine::general_purpose, Engine};
 use cozy_chess::BoardBuilder;
-use cozy_chess_types::{Square, Piece, Color, CastleRights};
-use crate::{chess::ChessState, game::Game};
+use cozy_chess_types::{Square, Piece, Color, CastleRights, Move};
+use crate::{chess::ChessState, engine::selfplay::GameOutcome, game::Game};
 use ndarray::Array1;
-use rusqlite::{Connection, backup::Backup, params};
-use anyhow::Result;
+use rand::{seq::SliceRandom, Rng};
+use rusqlite::{Connection, OptionalExtension, backup::Backup, params, params_from_iter};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt::Display};
 
 
 pub fn load_to_memory(conn: &Connection) -> Result<Connection> {
@@ -17,100 +20,670 @@ pub fn load_to_memory(conn: &Connection) -> Result<Connection> {
 }
 
 
-pub fn init(uri: &String) -> Connection {
-    Connection::open(uri).unwrap()
+enum Migration {
+    Sql(&'static str),
+    Code(fn(&Connection) -> Result<()>),
+}
+
+/// Schema changes, applied in order. The database records how many have been run in `schema_version`.
+const MIGRATIONS: &[Migration] = &[
+    Migration::Sql("CREATE TABLE IF NOT EXISTS chess_moves (
+        hash INTEGER PRIMARY KEY,
+        board TEXT NOT NULL,
+        wins INTEGER NOT NULL DEFAULT 0,
+        losses INTEGER NOT NULL DEFAULT 0
+    );"),
+    Migration::Sql("CREATE INDEX IF NOT EXISTS chess_moves_occurrences ON chess_moves (wins + losses);"),
+    Migration::Sql("CREATE TABLE IF NOT EXISTS replay_positions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        generation INTEGER NOT NULL,
+        fen TEXT NOT NULL,
+        policy TEXT NOT NULL,
+        value REAL NOT NULL
+    );"),
+    Migration::Sql("ALTER TABLE chess_moves ADD COLUMN draws INTEGER NOT NULL DEFAULT 0;
+    CREATE UNIQUE INDEX IF NOT EXISTS chess_moves_hash ON chess_moves (hash);
+    CREATE TABLE IF NOT EXISTS pgn_imports (
+        path TEXT PRIMARY KEY,
+        games INTEGER NOT NULL
+    );"),
+    Migration::Code(legacy_boards_to_fen),
+    Migration::Code(assign_splits),
+    // Occurrence filters count draws too.
+    Migration::Sql("DROP INDEX IF EXISTS chess_moves_occurrences;
+    CREATE INDEX chess_moves_occurrences ON chess_moves (wins + draws + losses);"),
+];
+
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Leading columns of `chess_moves` that queries rely on, in order.
+const CHESS_MOVES_COLUMNS: &[&str] = &["hash", "board", "wins", "losses"];
+
+/// Opens (creating if needed) the database at `uri` and brings its schema up to date.
+pub fn init(uri: &str) -> Result<Connection> {
+    let conn = Connection::open(uri)?;
+    migrate(&conn)?;
+    Ok(conn)
+}
+
+/// How many of `MIGRATIONS` have been applied. A database with a `chess_moves` table but no recorded version
+/// predates versioning and counts as version 1, so its table is checked against the first migration's layout.
+pub fn schema_version(conn: &Connection) -> Result<usize> {
+    let version: Option<usize> = conn
+        .query_row("SELECT MAX(version) FROM schema_version", [], |row| row.get(0))
+        .optional()?
+        .flatten();
+
+    Ok(match version {
+        Some(v) => v,
+        // Databases made before versioning only ever had the first migration's table.
+        None if table_exists(conn, "chess_moves")? => 1,
+        None => 0,
+    })
+}
+
+pub fn migrate(conn: &Connection) -> Result<()> {
+    conn.execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)", [])?;
+
+    let version = schema_version(conn)?;
+    if version > SCHEMA_VERSION {
+        bail!("Database schema version {version} is newer than the latest this build knows ({SCHEMA_VERSION})");
+    }
+    if version > 0 {
+        check_layout(conn)?;
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.unchecked_transaction()?;
+        match migration {
+            Migration::Sql(sql) => tx.execute_batch(sql)?,
+            Migration::Code(f) => f(&tx)?,
+        }
+        tx.execute("INSERT INTO schema_version (version) VALUES (?1)", params![i + 1])?;
+        tx.commit()?;
+    }
+
+    check_layout(conn)
+}
+
+fn table_exists(conn: &Connection, name: &str) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1", params![name], |row| row.get::<_, usize>(0),
+    )? > 0)
+}
+
+fn check_layout(conn: &Connection) -> Result<()> {
+    let mut stmnt = conn.prepare("SELECT name FROM pragma_table_info('chess_moves') ORDER BY cid")?;
+    let columns = stmnt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    if columns.len() < CHESS_MOVES_COLUMNS.len() || columns[..CHESS_MOVES_COLUMNS.len()] != *CHESS_MOVES_COLUMNS {
+        bail!(
+            "Table chess_moves has unexpected columns ({}), expected them to start with ({})",
+            columns.join(", "),
+            CHESS_MOVES_COLUMNS.join(", "),
+        );
+    }
+
+    Ok(())
+}
+
+/// Rewrites base64 boards as FEN, moving each row to the position's zobrist hash and merging its counts into
+/// any row already there. Rows that fail to decode are left as they were and reported on stderr.
+fn legacy_boards_to_fen(conn: &Connection) -> Result<()> {
+    let rows = conn
+        .prepare("SELECT hash, board, wins, draws, losses FROM chess_moves WHERE instr(board, ' ') = 0")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))?
+        .collect::<rusqlite::Result<Vec<(i64, String, u64, u64, u64)>>>()?;
+
+    // Every decodable row is removed before any is re-inserted, so a new hash can't land on a row that is still
+    // waiting to be moved.
+    let mut decoded = vec![];
+    let mut delete = conn.prepare("DELETE FROM chess_moves WHERE hash = ?1")?;
+    for (hash, board, wins, draws, losses) in rows {
+        if let Ok(state) = decode_legacy_board(&board) {
+            delete.execute(params![hash])?;
+            decoded.push((state, wins, draws, losses));
+        }
+    }
+
+    let mut insert = conn.prepare(
+        "INSERT INTO chess_moves (hash, board, wins, draws, losses) VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT(hash) DO UPDATE SET
+            wins = wins + excluded.wins,
+            draws = draws + excluded.draws,
+            losses = losses + excluded.losses",
+    )?;
+    for (state, wins, draws, losses) in decoded {
+        insert.execute(params![state.board.hash() as i64, encode_board(&state), wins, draws, losses])?;
+    }
+
+    let failed = legacy_boards(conn)?;
+    if !failed.is_empty() {
+        eprintln!("{} legacy boards failed to decode and were left as they were", failed.len());
+    }
+
+    Ok(())
+}
+
+/// Hashes of rows whose `board` is still in the legacy base64 format because it failed to decode.
+pub fn legacy_boards(conn: &Connection) -> Result<Vec<i64>> {
+    Ok(conn
+        .prepare("SELECT hash FROM chess_moves WHERE instr(board, ' ') = 0 ORDER BY hash")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    Sql(rusqlite::Error),
+    /// A `board` value that isn't a valid position.
+    InvalidBoard { board: String, reason: String },
+}
+
+impl Display for DatabaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sql(e) => write!(f, "Database error: {e}"),
+            Self::InvalidBoard { board, reason } => write!(f, "Invalid board `{board}`: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Sql(e) => Some(e),
+            Self::InvalidBoard { .. } => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DatabaseError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Sql(e)
+    }
+}
+
+impl DatabaseError {
+    fn invalid_board(board: &str, reason: impl Display) -> Self {
+        Self::InvalidBoard { board: board.to_owned(), reason: reason.to_string() }
+    }
+}
+
+/// Which held-out set a position belongs to. Assigned once from the position's hash, so a position never moves
+/// between sets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Split {
+    Train,
+    Validation,
+    Test,
+}
+
+impl Split {
+    /// Percent of positions in the validation and test sets.
+    const VALIDATION_PERCENT: i64 = 5;
+    const TEST_PERCENT: i64 = 5;
+
+    pub fn from_hash(hash: i64) -> Self {
+        match hash.rem_euclid(100) {
+            x if x < Self::TEST_PERCENT => Self::Test,
+            x if x < Self::TEST_PERCENT + Self::VALIDATION_PERCENT => Self::Validation,
+            _ => Self::Train,
+        }
+    }
+
+    pub(crate) fn id(self) -> i64 {
+        self as i64
+    }
+}
+
+fn assign_splits(conn: &Connection) -> Result<()> {
+    conn.execute_batch("ALTER TABLE chess_moves ADD COLUMN split INTEGER NOT NULL DEFAULT 0;
+    CREATE INDEX IF NOT EXISTS chess_moves_split ON chess_moves (split);")?;
+
+    let hashes = conn
+        .prepare("SELECT hash FROM chess_moves")?
+        .query_map([], |row| row.get::<_, i64>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut update = conn.prepare("UPDATE chess_moves SET split = ?1 WHERE hash = ?2")?;
+    for hash in hashes {
+        let split = Split::from_hash(hash);
+        if split != Split::Train {
+            update.execute(params![split.id(), hash])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Encodes a position for the `board` column as FEN, which keeps castling rights, en passant and the move counters.
+pub fn encode_board(state: &ChessState) -> String {
+    state.fen()
+}
+
+/// Inverse of `encode_board`.
+pub fn decode_board(text: &str) -> Result<ChessState, DatabaseError> {
+    ChessState::from_fen(text).map_err(|e| DatabaseError::invalid_board(text, e))
+}
+
+/// Decodes the base64 64-byte format boards were stored in before FEN. Castling rights, en passant and move
+/// counters were never stored; the side to move is inferred from the piece flag bits.
+fn decode_legacy_board(text: &str) -> Result<ChessState, DatabaseError> {
+    let buf = general_purpose::STANDARD.decode(text).map_err(|e| DatabaseError::invalid_board(text, e))?;
+    if buf.len() != 64 {
+        return Err(DatabaseError::invalid_board(text, format!("{} bytes, expected 64", buf.len())));
+    }
+
+    let mut player = Color::White;
+    let mut builder = BoardBuilder::empty();
+
+    *builder.castle_rights_mut(Color::White) = CastleRights::EMPTY;
+    *builder.castle_rights_mut(Color::Black) = CastleRights::EMPTY;
+
+    buf.iter().rev().enumerate().for_each(|(idx, c)| {
+        let c1 = c & 0b1111;
+        let sq = builder.square_mut(Square::ALL[7 - (idx % 8) + 8 * (idx / 8)]);
+
+        if c1 != 0 && c & 0b10000000 == 0 && c & 0b1000000 == 0 {
+            player = Color::Black;
+        }
+        *sq = match (c1, c & 0b10000000 == 0) {
+            (1, true ) => Some((Piece::Pawn,   Color::Black)),
+            (1, false) => Some((Piece::Pawn,   Color::White)),
+            (2, true ) => Some((Piece::Rook,   Color::Black)),
+            (2, false) => Some((Piece::Rook,   Color::White)),
+            (3, true ) => Some((Piece::Knight, Color::Black)),
+            (3, false) => Some((Piece::Knight, Color::White)),
+            (4, true ) => Some((Piece::Bishop, Color::Black)),
+            (4, false) => Some((Piece::Bishop, Color::White)),
+            (5, true ) => Some((Piece::Queen,  Color::Black)),
+            (5, false) => Some((Piece::Queen,  Color::White)),
+            (6, true ) => Some((Piece::King,   Color::Black)),
+            (6, false) => Some((Piece::King,   Color::White)),
+            _ => None,
+        };
+    });
+
+    builder.side_to_move = player;
+
+    let board = builder.build().map_err(|e| DatabaseError::invalid_board(text, format!("{e:?}")))?;
+    Ok(ChessState { board })
+}
+
+/// Turns a position's win/draw/loss counts into a training target and how much that target should count.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TargetSmoothing {
+    /// Beta(`prior_wins`, `prior_losses`) prior added to the counts, so rarely seen positions are pulled
+    /// towards its mean instead of to 0 or 1.
+    pub prior_wins: f32,
+    pub prior_losses: f32,
+    /// Occurrences at which a sample gets half of the full weight.
+    pub half_weight: f32,
+}
+
+impl Default for TargetSmoothing {
+    fn default() -> Self {
+        Self { prior_wins: 1.0, prior_losses: 1.0, half_weight: 4.0 }
+    }
 }
 
 #[derive(Debug)]
 pub struct Instance {
     pub board: ChessState,
     wins: u64,
+    draws: u64,
     losses: u64,
 }
 
 impl Instance {
-    pub fn from_str(text: &str, wins: u64, losses: u64) -> Self {
-        let buf = general_purpose::STANDARD.decode(text).unwrap();
-        Self::from_bytes(buf, wins, losses)
+    pub fn new(board: ChessState, wins: u64, draws: u64, losses: u64) -> Self {
+        Self { board, wins, draws, losses }
     }
 
-    pub fn from_bytes(buf: Vec<u8>, wins: u64, losses: u64) -> Self {
-        let mut player = Color::White;
-        let mut builder = BoardBuilder::empty();
-
-        *builder.castle_rights_mut(Color::White) = CastleRights::EMPTY;
-        *builder.castle_rights_mut(Color::Black) = CastleRights::EMPTY;
-
-        buf.iter().rev().enumerate().for_each(|(idx, c)| {
-            let c1 = c & 0b1111;
-            let sq = builder.square_mut(Square::ALL[7 - (idx % 8) + 8 * (idx / 8)]);
+    /// Builds an instance from a `board` column value.
+    pub fn from_str(text: &str, wins: u64, draws: u64, losses: u64) -> Result<Self, DatabaseError> {
+        Ok(Self::new(decode_board(text)?, wins, draws, losses))
+    }
 
-            if c1 != 0 {
-                if c & 0b10000000 == 0 && c & 0b1000000 == 0 {
-                    player = Color::Black;
-                }
-            }
-            *sq = match (c1, c & 0b10000000 == 0) {
-                (1, true ) => Some((Piece::Pawn,   Color::Black)),
-                (1, false) => Some((Piece::Pawn,   Color::White)),
-                (2, true ) => Some((Piece::Rook,   Color::Black)),
-                (2, false) => Some((Piece::Rook,   Color::White)),
-                (3, true ) => Some((Piece::Knight, Color::Black)),
-                (3, false) => Some((Piece::Knight, Color::White)),
-                (4, true ) => Some((Piece::Bishop, Color::Black)),
-                (4, false) => Some((Piece::Bishop, Color::White)),
-                (5, true ) => Some((Piece::Queen,  Color::Black)),
-                (5, false) => Some((Piece::Queen,  Color::White)),
-                (6, true ) => Some((Piece::King,   Color::Black)),
-                (6, false) => Some((Piece::King,   Color::White)),
-                _ => None,
-            };
-        });
-
-        builder.side_to_move = player;
-
-        return Self {
-            board: ChessState { board: builder.build().unwrap() },
-            wins,
-            losses,
-        };
+    /// The colour-flipped position, with wins and losses swapped to match.
+    pub fn flipped(&self) -> Self {
+        Self::new(self.board.flipped(), self.losses, self.draws, self.wins)
     }
 
+    pub fn occurrences(&self) -> u64 {
+        self.wins + self.draws + self.losses
+    }
 
-    pub fn winrate(&self) -> (f32, f32) {
-        let t = (self.wins + self.losses) as f32;
-        let w = self.wins as f32 / t;
+    /// Posterior mean of White's score, counting a draw as half a win, as (win, loss) probabilities.
+    pub fn target(&self, smoothing: &TargetSmoothing) -> (f32, f32) {
+        let w = (self.wins as f32 + 0.5 * self.draws as f32 + smoothing.prior_wins)
+            / (self.occurrences() as f32 + smoothing.prior_wins + smoothing.prior_losses);
         (w, 1.0 - w)
     }
 
+    /// Loss weight in [0, 1), growing with how often the position was seen.
+    pub fn weight(&self, smoothing: &TargetSmoothing) -> f32 {
+        let n = self.occurrences() as f32;
+        n / (n + smoothing.half_weight)
+    }
+
     pub fn state(&self) -> Array1<f32> {
         self.board.state()
     }
 }
 
-pub fn get_batch(conn: &Connection, min_occurences: usize, size: usize) -> Vec<Instance> {
-    let mut stmnt = conn.prepare(
-        "SELECT * FROM chess_moves 
-        WHERE wins + losses > ?1 
-        ORDER BY RANDOM() LIMIT ?2", 
-    ).unwrap();
-
-    stmnt.query_map(
-        params![
-            min_occurences, 
-            size
-        ],
-        |row| Ok({
-            // let hash: i64 = row.get(0).unwrap();
-            let board = &row.get::<_, String>(1).unwrap();
-            let wins: u64 = row.get(2).unwrap();
-            let losses: u64 = row.get(3).unwrap();
-            
-            Instance::from_str(&board, wins, losses)
+#[derive(Debug, Default)]
+pub struct Batch {
+    pub instances: Vec<Instance>,
+    /// Hashes of rows that were skipped because they couldn't be decoded.
+    pub failed: Vec<i64>,
+}
+
+impl Batch {
+    pub fn skipped(&self) -> usize {
+        self.failed.len()
+    }
+}
+
+/// Reads a row of `board, wins, draws, losses`.
+fn read_instance(row: &rusqlite::Row) -> Result<Instance, DatabaseError> {
+    let board: String = row.get(0)?;
+    Instance::from_str(&board, row.get(1)?, row.get(2)?, row.get(3)?)
+}
+
+/// Samples up to `size` random positions from `split` seen more than `min_occurences` times. Rows that fail to
+/// decode are skipped and reported in `Batch::failed` rather than failing the whole batch; SQLite errors still
+/// fail it.
+///
+/// Every call reads all matching ids, so repeated sampling should go through `sampler::EpochSampler` instead.
+pub fn get_batch<R: Rng>(
+    conn: &Connection,
+    split: Split,
+    min_occurences: usize,
+    size: usize,
+    rng: &mut R,
+) -> Result<Batch, DatabaseError> {
+    let ids = conn
+        .prepare("SELECT hash FROM chess_moves WHERE wins + draws + losses > ?1 AND split = ?2 ORDER BY hash")?
+        .query_map(params![min_occurences, split.id()], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+    let mut stmnt = conn.prepare_cached("SELECT board, wins, draws, losses FROM chess_moves WHERE hash = ?1")?;
+    let mut batch = Batch::default();
+    for &id in ids.choose_multiple(rng, size) {
+        match stmnt.query_row(params![id], |row| Ok(read_instance(row)))? {
+            Ok(x) => batch.instances.push(x),
+            Err(_) => batch.failed.push(id),
+        }
+    }
+
+    Ok(batch)
+}
+
+/// Adds one game's `outcome` to the counters of `state`, inserting the position if it is new.
+pub fn upsert_position(conn: &Connection, state: &ChessState, outcome: GameOutcome) -> Result<()> {
+    let (wins, draws, losses) = match outcome {
+        GameOutcome::WhiteWin => (1, 0, 0),
+        GameOutcome::Draw => (0, 1, 0),
+        GameOutcome::BlackWin => (0, 0, 1),
+    };
+    add_counts(conn, state, wins, draws, losses)
+}
+
+/// Adds `wins`, `draws` and `losses` games to the counters of `state`, inserting the position if it is new.
+pub fn add_counts(conn: &Connection, state: &ChessState, wins: u64, draws: u64, losses: u64) -> Result<()> {
+    let hash = state.board.hash() as i64;
+
+    conn.prepare_cached(
+        "INSERT INTO chess_moves (hash, board, wins, draws, losses, split) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+        ON CONFLICT(hash) DO UPDATE SET
+            wins = wins + excluded.wins,
+            draws = draws + excluded.draws,
+            losses = losses + excluded.losses",
+    )?.execute(params![hash, encode_board(state), wins, draws, losses, Split::from_hash(hash).id()])?;
+
+    Ok(())
+}
+
+/// How often each legal move of `state` was played, going by how many games reached the position it leads to.
+/// Counts include games that reached those positions by other move orders. Empty when none were reached.
+pub fn move_distribution(conn: &Connection, state: &ChessState) -> Result<Vec<(Move, f32)>, DatabaseError> {
+    let children = state.moves().into_iter()
+        .map(|mv| {
+            let mut child = state.board.clone();
+            child.play_unchecked(mv);
+            (mv, child.hash() as i64)
         })
-    )   
-        .unwrap()
-        .filter_map(|x| x.ok())
-        .collect()
-    
-}
\ No newline at end of file
+        .collect::<Vec<_>>();
+    if children.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let placeholders = vec!["?"; children.len()].join(",");
+    let found = conn
+        .prepare(&format!("SELECT hash, wins + draws + losses FROM chess_moves WHERE hash IN ({placeholders})"))?
+        .query_map(params_from_iter(children.iter().map(|x| x.1)), |row| Ok((row.get::<_, i64>(0)?, row.get::<_, u64>(1)?)))?
+        .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+
+    let counts = children.into_iter()
+        .filter_map(|(mv, hash)| found.get(&hash).filter(|x| **x > 0).map(|x| (mv, *x as f32)))
+        .collect::<Vec<_>>();
+    let total = counts.iter().map(|x| x.1).sum::<f32>();
+    Ok(counts.into_iter().map(|(mv, x)| (mv, x / total)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(fen: &str) {
+        let state = decode_board(fen).unwrap();
+        let text = encode_board(&state);
+        assert_eq!(text, fen);
+        assert_eq!(decode_board(&text).unwrap().board, state.board);
+    }
+
+    #[test]
+    fn round_trips_start_position() {
+        round_trip("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+    }
+
+    #[test]
+    fn round_trips_castling_en_passant_and_counters() {
+        round_trip("r3k2r/pp1n1ppp/8/2pP4/8/8/PPP2PPP/R3K2R w Kq c6 0 12");
+        round_trip("4k3/8/8/8/8/8/8/4K2R b K - 37 80");
+    }
+
+    #[test]
+    fn rejects_invalid_boards() {
+        assert!(decode_board("not a fen").is_err());
+        assert!(decode_board("8/8/8/8/8/8/8/8 w - - 0 1").is_err());
+    }
+
+    #[test]
+    fn migrates_legacy_boards_to_fen() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE chess_moves (hash INTEGER PRIMARY KEY, board TEXT NOT NULL, wins INTEGER, losses INTEGER);",
+        ).unwrap();
+
+        // The start position after 1. e4, Black to move, in the legacy byte layout.
+        let mut bytes = [0x40u8; 64];
+        let back = [2, 3, 4, 5, 6, 4, 3, 2];
+        for (file, &kind) in back.iter().enumerate() {
+            bytes[file] = kind;
+            bytes[8 + file] = 1;
+            bytes[48 + file] = 0xC1;
+            bytes[56 + file] = 0xC0 | kind;
+        }
+        bytes[52] = 0x40;
+        bytes[36] = 0xC1;
+        let legacy = general_purpose::STANDARD.encode(bytes);
+
+        // The same position already stored under its zobrist hash, which the legacy row gets merged into.
+        let e4 = ChessState::from_fen("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b - - 0 1").unwrap();
+        let hash = e4.board.hash() as i64;
+        conn.execute(
+            "INSERT INTO chess_moves VALUES (1, ?1, 3, 2), (2, 'garbage', 0, 0), (?2, ?3, 1, 1)",
+            params![legacy, hash, encode_board(&e4)],
+        ).unwrap();
+        migrate(&conn).unwrap();
+
+        let row: (String, u64, u64) = conn
+            .query_row(
+                "SELECT board, wins, losses FROM chess_moves WHERE hash = ?1", params![hash],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(row, ("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b - - 0 1".to_owned(), 4, 3));
+        let moved: usize = conn.query_row("SELECT COUNT(*) FROM chess_moves WHERE hash = 1", [], |row| row.get(0)).unwrap();
+        assert_eq!(moved, 0);
+
+        let board: String = conn.query_row("SELECT board FROM chess_moves WHERE hash = 2", [], |row| row.get(0)).unwrap();
+        assert_eq!(board, "garbage");
+        assert_eq!(legacy_boards(&conn).unwrap(), vec![2]);
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn unversioned_databases_are_treated_as_version_one() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE chess_moves (hash INTEGER PRIMARY KEY, board TEXT NOT NULL, wins INTEGER, losses INTEGER);",
+        ).unwrap();
+        migrate(&conn).unwrap();
+
+        // Only the migrations after the first were run and recorded.
+        let (first, last): (usize, usize) = conn
+            .query_row("SELECT MIN(version), MAX(version) FROM schema_version", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!((first, last), (2, SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn migrate_rejects_unexpected_layouts() {
+        // An unversioned table is assumed to be the first migration's, so its columns have to match.
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE chess_moves (id INTEGER PRIMARY KEY, fen TEXT NOT NULL);").unwrap();
+        let error = migrate(&conn).unwrap_err().to_string();
+        assert!(error.contains("unexpected columns (id, fen)"), "{error}");
+
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE chess_moves (board TEXT NOT NULL, hash INTEGER PRIMARY KEY, wins INTEGER, losses INTEGER);
+            CREATE TABLE schema_version (version INTEGER NOT NULL);
+            INSERT INTO schema_version VALUES (1);",
+        ).unwrap();
+        assert!(migrate(&conn).unwrap_err().to_string().contains("unexpected columns"));
+    }
+
+    #[test]
+    fn migrate_rejects_newer_schema_versions() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        conn.execute("INSERT INTO schema_version (version) VALUES (?1)", params![SCHEMA_VERSION + 1]).unwrap();
+
+        let error = migrate(&conn).unwrap_err().to_string();
+        assert!(error.contains(&format!("version {} is newer", SCHEMA_VERSION + 1)), "{error}");
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION + 1);
+    }
+
+    #[test]
+    fn get_batch_skips_invalid_rows() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        upsert_position(&conn, &ChessState::default(), GameOutcome::WhiteWin).unwrap();
+        let split = Split::from_hash(ChessState::default().board.hash() as i64);
+        conn.execute(
+            "INSERT INTO chess_moves (hash, board, wins, losses, split) VALUES (7, 'garbage', 1, 0, ?1)", params![split.id()],
+        ).unwrap();
+
+        let batch = get_batch(&conn, split, 0, 10, &mut rand::thread_rng()).unwrap();
+        assert_eq!(batch.instances.len(), 1);
+        assert_eq!(batch.failed, vec![7]);
+    }
+
+    #[test]
+    fn splits_are_stored_by_hash() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+
+        let mut state = ChessState::default();
+        let mut hashes = vec![];
+        for _ in 0..40 {
+            upsert_position(&conn, &state, GameOutcome::Draw).unwrap();
+            hashes.push(state.board.hash() as i64);
+            let mut mv = None;
+            state.board.generate_moves(|mvs| {
+                mv = mvs.into_iter().last();
+                true
+            });
+            state.board.play_unchecked(mv.unwrap());
+        }
+
+        for hash in hashes {
+            let split: i64 = conn.query_row("SELECT split FROM chess_moves WHERE hash = ?1", params![hash], |row| row.get(0)).unwrap();
+            assert_eq!(split, Split::from_hash(hash).id());
+        }
+    }
+
+    #[test]
+    fn drawn_positions_pass_occurrence_filters() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        let state = ChessState::default();
+        for _ in 0..3 {
+            upsert_position(&conn, &state, GameOutcome::Draw).unwrap();
+        }
+
+        let split = Split::from_hash(state.board.hash() as i64);
+        assert_eq!(get_batch(&conn, split, 2, 10, &mut rand::thread_rng()).unwrap().instances.len(), 1);
+        assert!(get_batch(&conn, split, 3, 10, &mut rand::thread_rng()).unwrap().instances.is_empty());
+    }
+
+    #[test]
+    fn targets_are_smoothed_and_weighted() {
+        let smoothing = TargetSmoothing::default();
+
+        let unseen = Instance::new(ChessState::default(), 0, 0, 0);
+        assert_eq!(unseen.target(&smoothing), (0.5, 0.5));
+        assert_eq!(unseen.weight(&smoothing), 0.0);
+
+        let rare = Instance::new(ChessState::default(), 2, 0, 0);
+        let common = Instance::new(ChessState::default(), 2000, 0, 0);
+        assert!(rare.target(&smoothing).0 < common.target(&smoothing).0);
+        assert!(common.target(&smoothing).0 < 1.0);
+        assert!(rare.weight(&smoothing) < common.weight(&smoothing));
+
+        // Draws count as half a win and towards how often the position was seen.
+        let drawish = Instance::new(ChessState::default(), 2, 96, 2);
+        assert_eq!(drawish.occurrences(), 100);
+        assert!((drawish.target(&smoothing).0 - 0.5).abs() < 1e-6);
+        assert_eq!(drawish.weight(&smoothing), 100.0 / 104.0);
+        assert_eq!(drawish.flipped().target(&smoothing), drawish.target(&smoothing));
+    }
+
+    #[test]
+    fn move_distribution_counts_children() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+
+        let start = ChessState::default();
+        for (mv, games) in [("e2e4", 3), ("d2d4", 1)] {
+            let mut child = start.clone();
+            child.board.play_unchecked(mv.parse().unwrap());
+            for _ in 0..games {
+                upsert_position(&conn, &child, GameOutcome::Draw).unwrap();
+            }
+        }
+
+        let mut moves = move_distribution(&conn, &start).unwrap();
+        moves.sort_by(|a, b| b.1.total_cmp(&a.1));
+        assert_eq!(moves, vec![("e2e4".parse().unwrap(), 0.75), ("d2d4".parse().unwrap(), 0.25)]);
+        assert!(move_distribution(&conn, &decode_board("4k3/8/8/8/8/8/8/4K3 w - - 0 1").unwrap()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,184 @@
+use cozy_chess_types::Color;
+
+use crate::chess::ChessState;
+
+use super::selfplay::GameOutcome;
+
+#[derive(Debug, Clone)]
+pub struct AdjudicationConfig {
+    /// Resign once the side to move's root value stays below this for `resign_moves` of its moves.
+    pub resign_threshold: Option<f32>,
+    pub resign_moves: usize,
+    /// Fraction of self-play games played out with resignation off, to measure how often it would have been wrong.
+    pub resign_disabled_fraction: f32,
+    /// Adjudicate a draw once the root value stays within this distance of 0.5 for `draw_moves` plies.
+    pub draw_margin: Option<f32>,
+    pub draw_moves: usize,
+    /// Value-based draws are not adjudicated before this ply.
+    pub draw_min_ply: usize,
+    pub insufficient_material: bool,
+    pub max_plies: Option<usize>,
+}
+
+impl Default for AdjudicationConfig {
+    fn default() -> Self {
+        Self {
+            resign_threshold: Some(0.05),
+            resign_moves: 4,
+            resign_disabled_fraction: 0.1,
+            draw_margin: Some(0.02),
+            draw_moves: 12,
+            draw_min_ply: 80,
+            insufficient_material: true,
+            max_plies: Some(512),
+        }
+    }
+}
+
+impl AdjudicationConfig {
+    /// Only ends games by the rules of chess.
+    pub fn none() -> Self {
+        Self {
+            resign_threshold: None,
+            resign_moves: 0,
+            resign_disabled_fraction: 0.0,
+            draw_margin: None,
+            draw_moves: 0,
+            draw_min_ply: 0,
+            insufficient_material: false,
+            max_plies: None,
+        }
+    }
+}
+
+/// Tracks root values over a game and decides when it can be ended early.
+#[derive(Debug, Clone)]
+pub struct Adjudicator {
+    config: AdjudicationConfig,
+    resign_enabled: bool,
+    low_streak: [usize; 2],
+    draw_streak: usize,
+    would_resign: Option<Color>,
+}
+
+impl Adjudicator {
+    pub fn new(config: AdjudicationConfig, resign_enabled: bool) -> Self {
+        Self { config, resign_enabled, low_streak: [0; 2], draw_streak: 0, would_resign: None }
+    }
+
+    /// The side that would have resigned in a game where resignation was disabled.
+    pub fn would_resign(&self) -> Option<Color> {
+        self.would_resign
+    }
+
+    /// Checks rules that don't need a search: game length and insufficient material.
+    pub fn check_position(&self, state: &ChessState, ply: usize) -> Option<GameOutcome> {
+        if self.config.max_plies.is_some_and(|x| ply >= x) { return Some(GameOutcome::Draw) }
+        if self.config.insufficient_material && state.insufficient_material() { return Some(GameOutcome::Draw) }
+        None
+    }
+
+    /// Records the searched `score` (for the side to move) at `ply` and returns an outcome if the game should end.
+    pub fn update(&mut self, state: &ChessState, ply: usize, score: f32) -> Option<GameOutcome> {
+        let side = state.board.side_to_move();
+
+        if let Some(threshold) = self.config.resign_threshold {
+            let streak = &mut self.low_streak[side as usize];
+            *streak = if score < threshold { *streak + 1 } else { 0 };
+
+            if *streak >= self.config.resign_moves.max(1) {
+                if self.resign_enabled {
+                    return Some(match side {
+                        Color::White => GameOutcome::BlackWin,
+                        Color::Black => GameOutcome::WhiteWin,
+                    });
+                }
+                self.would_resign.get_or_insert(side);
+            }
+        }
+
+        if let Some(margin) = self.config.draw_margin {
+            self.draw_streak = if (score - 0.5).abs() < margin { self.draw_streak + 1 } else { 0 };
+            if ply >= self.config.draw_min_ply && self.draw_streak >= self.config.draw_moves.max(1) {
+                return Some(GameOutcome::Draw);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AdjudicationConfig {
+        AdjudicationConfig { resign_threshold: Some(0.05), resign_moves: 3, draw_margin: Some(0.02), draw_moves: 4, draw_min_ply: 10, ..AdjudicationConfig::none() }
+    }
+
+    /// The initial position with White to move, and after 1. e4 with Black to move.
+    fn positions() -> (ChessState, ChessState) {
+        (ChessState::default(), ChessState::from_fen("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1").unwrap())
+    }
+
+    #[test]
+    fn resigns_after_a_streak_of_low_scores() {
+        let (white, black) = positions();
+        let mut adjudicator = Adjudicator::new(config(), true);
+
+        // Black's scores don't break White's streak, but a single recovery does.
+        assert_eq!(adjudicator.update(&white, 0, 0.01), None);
+        assert_eq!(adjudicator.update(&black, 1, 0.9), None);
+        assert_eq!(adjudicator.update(&white, 2, 0.06), None);
+        assert_eq!(adjudicator.update(&white, 4, 0.04), None);
+        assert_eq!(adjudicator.update(&white, 6, 0.04), None);
+        assert_eq!(adjudicator.update(&white, 8, 0.04), Some(GameOutcome::BlackWin));
+
+        let mut adjudicator = Adjudicator::new(config(), true);
+        for ply in 0..2 {
+            assert_eq!(adjudicator.update(&black, ply, 0.0), None);
+        }
+        assert_eq!(adjudicator.update(&black, 2, 0.0), Some(GameOutcome::WhiteWin));
+    }
+
+    #[test]
+    fn disabled_resignation_is_only_recorded() {
+        let (white, _) = positions();
+        let mut adjudicator = Adjudicator::new(config(), false);
+
+        for ply in 0..5 {
+            assert_eq!(adjudicator.update(&white, ply, 0.0), None);
+        }
+        assert_eq!(adjudicator.would_resign(), Some(Color::White));
+    }
+
+    #[test]
+    fn draws_need_a_streak_past_the_minimum_ply() {
+        let (white, _) = positions();
+
+        // A long streak of level scores before ply 10 doesn't end the game, the first one after it does.
+        let mut adjudicator = Adjudicator::new(config(), true);
+        for ply in 0..10 {
+            assert_eq!(adjudicator.update(&white, ply, 0.51), None);
+        }
+        assert_eq!(adjudicator.update(&white, 10, 0.49), Some(GameOutcome::Draw));
+
+        // Scores outside the margin restart the streak.
+        let mut adjudicator = Adjudicator::new(config(), true);
+        for (ply, score) in [0.5, 0.5, 0.5, 0.53, 0.5, 0.5, 0.5].into_iter().enumerate() {
+            assert_eq!(adjudicator.update(&white, ply + 10, score), None);
+        }
+        assert_eq!(adjudicator.update(&white, 17, 0.5), Some(GameOutcome::Draw));
+    }
+
+    #[test]
+    fn checks_game_length_and_material() {
+        let bare_kings = ChessState::from_fen("4k3/8/8/8/8/8/8/4K3 w - - 0 1").unwrap();
+        let adjudicator = Adjudicator::new(AdjudicationConfig { max_plies: Some(100), insufficient_material: true, ..AdjudicationConfig::none() }, true);
+
+        assert_eq!(adjudicator.check_position(&ChessState::default(), 99), None);
+        assert_eq!(adjudicator.check_position(&ChessState::default(), 100), Some(GameOutcome::Draw));
+        assert_eq!(adjudicator.check_position(&bare_kings, 0), Some(GameOutcome::Draw));
+        assert_eq!(Adjudicator::new(AdjudicationConfig::none(), true).check_position(&bare_kings, 1000), None);
+    }
+}
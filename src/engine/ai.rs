@@ -1,60 +1,122 @@
-use std::{fs::File, io::{Read, Write}};
+use std::{borrow::Cow, fs::File, io::{Read, Write}};
 
+use cozy_chess_types::{Color, Move};
 use ndarray::{arr1, Array1};
 use serde::{Deserialize, Serialize};
 use anyhow::Result;
+use rand::Rng;
 
-use crate::neural_net::{Layer, Activation};
+use crate::{chess::ChessState, game::Game, neural_net::{softmax, Layer, Activation, Cost, Initializer, TrainStats}};
 
 use super::tools::Tools;
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Thod {
-    policy: Layer,
-    value: Layer,
-}
+/// Size of the encoded position fed to the network.
+const INPUTS: usize = 1089;
 
-impl Thod {
-    pub fn from_shape(pol: Vec<usize>, val: Vec<usize>) -> Self {
-        let mut policy = Layer::random(1089, pol[0], Activation::LeakyReLU);
-        let mut value  = Layer::random(1089, val[0], Activation::LeakyReLU);
+/// Relative weight of each head's term in the combined loss.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LossWeights {
+    pub policy: f32,
+    pub value: f32,
+}
 
-        for i in pol.iter().skip(1) {
-            policy.add_random_layer(*i, Activation::LeakyReLU);
-        }
+impl Default for LossWeights {
+    fn default() -> Self {
+        Self { policy: 1.0, value: 1.0 }
+    }
+}
 
-        for i in val.iter().skip(1) {
-            value.add_random_layer(*i, Activation::LeakyReLU);
-        }
+/// How the policy's move distribution compares with a target one.
+#[derive(Debug, Clone, Copy)]
+pub struct MovePrediction {
+    /// Cross-entropy of the policy's distribution against the target.
+    pub loss: f32,
+    /// Position of the target's most likely move in the policy's ranking, 0 for its favourite.
+    pub rank: usize,
+}
 
-        policy.add_random_layer(2, Activation::Softmax);
-        value.add_random_layer(2, Activation::Softmax);
+/// The legal moves of `state` with their share of `target`, renormalised. `None` when no legal move has any.
+fn masked_target(state: &ChessState, target: &[(Move, f32)]) -> Option<(Vec<Move>, Vec<f32>)> {
+    let moves = state.moves();
+    let mut probs: Vec<f32> = moves.iter()
+        .map(|mv| target.iter().filter(|(x, _)| x == mv).map(|(_, p)| p.max(0.0)).sum())
+        .collect();
 
-        value.scale(0.4);
-        policy.scale(0.3);
+    let total = probs.iter().sum::<f32>();
+    if total <= 0.0 { return None }
+    probs.iter_mut().for_each(|x| *x /= total);
+    Some((moves, probs))
+}
 
-        Self { policy, value }
+/// 1 when White is to move and -1 when Black is, turning White's point of view into the mover's.
+fn mover_sign(state: &ChessState) -> f32 {
+    match state.board.side_to_move() {
+        Color::White => 1.0,
+        Color::Black => -1.0,
     }
+}
 
-    pub fn from_shape_linear(pol: Vec<usize>, val: Vec<usize>) -> Self {
-        let mut policy = Layer::random(1089, pol[0], Activation::Linear);
-        let mut value  = Layer::random(1089, val[0], Activation::Linear);
+fn cross_entropy(probs: &[f32], target: &[f32]) -> f32 {
+    probs.iter().zip(target).map(|(p, t)| -t * p.max(f32::MIN_POSITIVE).ln()).sum()
+}
 
-        for i in pol.iter().skip(1) {
-            policy.add_random_layer(*i, Activation::Linear);
-        }
+/// Policy and value networks, either as two separate towers or as two heads on a shared trunk.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Thod {
+    /// Shared layers both heads read from. Without one, each head reads the encoded position itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trunk: Option<Layer>,
+    policy: Layer,
+    value: Layer,
+    /// How the network was initialised; `None` for networks saved before this was recorded.
+    #[serde(default)]
+    init: Option<Initializer>,
+}
 
-        for i in val.iter().skip(1) {
-            value.add_random_layer(*i, Activation::Linear);
-        }
+/// `hidden` layers after `inputs`, ending in a two-way softmax.
+fn head<R: Rng>(inputs: usize, hidden: &[usize], activation: Activation, init: &Initializer, rng: &mut R) -> Layer {
+    let Some((first, rest)) = hidden.split_first() else {
+        return Layer::random(inputs, 2, Activation::Softmax, init, rng);
+    };
+
+    let mut layer = Layer::random(inputs, *first, activation.clone(), init, rng);
+    for i in rest {
+        layer.add_random_layer(*i, activation.clone(), init, rng);
+    }
+    layer.add_random_layer(2, Activation::Softmax, init, rng);
+    layer
+}
+
+impl Thod {
+    /// Two separate towers with the given hidden layer sizes.
+    pub fn from_shape<R: Rng>(pol: Vec<usize>, val: Vec<usize>, init: Initializer, rng: &mut R) -> Self {
+        let policy = head(INPUTS, &pol, Activation::LeakyReLU, &init, rng);
+        let value = head(INPUTS, &val, Activation::LeakyReLU, &init, rng);
+        Self { trunk: None, policy, value, init: Some(init) }
+    }
 
-        policy.add_random_layer(2, Activation::Softmax);
-        value.add_random_layer(2, Activation::Softmax);
+    pub fn from_shape_linear<R: Rng>(pol: Vec<usize>, val: Vec<usize>, init: Initializer, rng: &mut R) -> Self {
+        let policy = head(INPUTS, &pol, Activation::Linear, &init, rng);
+        let value = head(INPUTS, &val, Activation::Linear, &init, rng);
+        Self { trunk: None, policy, value, init: Some(init) }
+    }
 
-        value.scale(0.4);
-        policy.scale(0.3);
+    /// A shared trunk with the `trunk` layer sizes, topped by policy and value heads with the given hidden
+    /// layer sizes. Falls back to two towers when `trunk` is empty.
+    pub fn with_trunk<R: Rng>(trunk: Vec<usize>, pol: Vec<usize>, val: Vec<usize>, init: Initializer, rng: &mut R) -> Self {
+        let Some((first, rest)) = trunk.split_first() else {
+            return Self::from_shape(pol, val, init, rng);
+        };
+
+        let mut body = Layer::random(INPUTS, *first, Activation::LeakyReLU, &init, rng);
+        for i in rest {
+            body.add_random_layer(*i, Activation::LeakyReLU, &init, rng);
+        }
 
-        Self { policy, value }
+        let features = *trunk.last().unwrap();
+        let policy = head(features, &pol, Activation::LeakyReLU, &init, rng);
+        let value = head(features, &val, Activation::LeakyReLU, &init, rng);
+        Self { trunk: Some(body), policy, value, init: Some(init) }
     }
 
     pub fn from_file(path: &str) -> Result<Self> {
@@ -67,34 +129,225 @@ impl Thod {
     pub fn save(&self, path: &str) -> Result<()> {
         let data = serde_json::to_vec_pretty(self)?;
         let mut file = File::create(path)?;
-        file.write(&data)?;
+        file.write_all(&data)?;
 
         Ok(())
     }
 
-    pub fn train_policy(&mut self, state: &Array1<f32>, outcome: f32, lr: f32) {
-        self.policy.train(state, &arr1(&[outcome, 1.0 - outcome]), &crate::neural_net::Cost::CrossEntropy, lr);
+    pub fn has_trunk(&self) -> bool {
+        self.trunk.is_some()
+    }
+
+    /// What the heads read: the trunk's output, or the encoded position itself.
+    fn features<'a>(&self, state: &'a Array1<f32>) -> Cow<'a, Array1<f32>> {
+        match &self.trunk {
+            Some(trunk) => Cow::Owned(trunk.predict(state)),
+            None => Cow::Borrowed(state),
+        }
+    }
+
+    /// Both heads' scores, running the trunk once.
+    pub fn predict(&self, state: &Array1<f32>) -> (f32, f32) {
+        let features = self.features(state);
+        (self.policy.predict(&features)[0], self.value.predict(&features)[0])
+    }
+
+    /// One step on the combined loss `weights.policy * policy loss + weights.value * value loss`, with each head
+    /// stepped at its own learning rate. A shared trunk gets the sum of the heads' gradients, each scaled by
+    /// its head's weight and learning rate. Heads with a zero weight or learning rate are left out.
+    pub fn train(&mut self, state: &Array1<f32>, outcome: f32, policy_lr: f32, value_lr: f32, weights: LossWeights) -> (TrainStats, TrainStats) {
+        let features = self.features(state).into_owned();
+        let target = arr1(&[outcome, 1.0 - outcome]);
+        let mut dfeatures = Array1::zeros(features.len());
+
+        let mut step = |head: &mut Layer, lr: f32| {
+            if lr == 0.0 { return TrainStats::default() }
+            let (stats, di) = head.train_with_input_grad(&features, &target, &Cost::CrossEntropy, lr);
+            dfeatures.scaled_add(lr, &di);
+            stats
+        };
+        let policy = step(&mut self.policy, policy_lr * weights.policy);
+        let value = step(&mut self.value, value_lr * weights.value);
+
+        if let Some(trunk) = &mut self.trunk {
+            trunk.train_on_gradient(state, &dfeatures, 1.0);
+        }
+        (policy, value)
+    }
+
+    /// Encoded position after each move.
+    fn children(state: &ChessState, moves: &[Move]) -> Vec<Array1<f32>> {
+        moves.iter()
+            .map(|mv| {
+                let mut child = state.clone();
+                child.board.play_unchecked(*mv);
+                child.state()
+            })
+            .collect()
+    }
+
+    /// Logit of the policy head's score for White, from what the heads read.
+    fn policy_logit(&self, features: &Array1<f32>) -> f32 {
+        let z = self.policy.logits(features);
+        z[0] - z[1]
+    }
+
+    /// Logit of playing each move: the policy head's logit for the position it leads to, from the mover's
+    /// point of view. Their softmax is the policy's distribution over the moves.
+    pub fn move_logits(&self, state: &ChessState, moves: &[Move]) -> Vec<f32> {
+        let sign = mover_sign(state);
+        Self::children(state, moves).iter()
+            .map(|child| sign * self.policy_logit(&self.features(child)))
+            .collect()
+    }
+
+    /// Compares the policy's distribution over the legal moves of `state` with `target`.
+    pub fn predict_moves(&self, state: &ChessState, target: &[(Move, f32)]) -> Option<MovePrediction> {
+        let (moves, target) = masked_target(state, target)?;
+        let probs = softmax(&self.move_logits(state, &moves));
+
+        let best = (0..moves.len()).max_by(|a, b| target[*a].total_cmp(&target[*b]))?;
+        Some(MovePrediction {
+            loss: cross_entropy(&probs, &target),
+            rank: probs.iter().filter(|p| **p > probs[best]).count(),
+        })
+    }
+
+    /// One step of cross-entropy between `target` and the softmax of the move logits, masked to the legal
+    /// moves of `state`. Illegal moves in `target` are ignored and the rest renormalised; when none are left
+    /// nothing is trained. The gradient norm adds up the steps taken for each move.
+    pub fn train_moves(&mut self, state: &ChessState, target: &[(Move, f32)], lr: f32) -> TrainStats {
+        let Some((moves, target)) = masked_target(state, target) else { return TrainStats::default() };
+        let sign = mover_sign(state);
+
+        let children = Self::children(state, &moves);
+        let features: Vec<_> = children.iter().map(|x| self.features(x).into_owned()).collect();
+        let logits: Vec<_> = features.iter().map(|x| sign * self.policy_logit(x)).collect();
+        let probs = softmax(&logits);
+
+        let mut stats = TrainStats {
+            loss: cross_entropy(&probs, &target),
+            grad_norm: 0.0,
+        };
+        for ((child, features), (p, t)) in children.iter().zip(&features).zip(probs.iter().zip(&target)) {
+            // d loss / d logit is p - t; the move's logit is the difference of the head's two logits.
+            let g = sign * (p - t);
+            let (norm, di) = self.policy.train_on_logits(features, &arr1(&[g, -g]), lr);
+            stats.grad_norm += norm * norm;
+
+            if let Some(trunk) = &mut self.trunk {
+                stats.grad_norm += trunk.train_on_gradient(child, &di, lr).powi(2);
+            }
+        }
+
+        stats.grad_norm = stats.grad_norm.sqrt();
+        stats
+    }
+
+    pub fn train_policy(&mut self, state: &Array1<f32>, outcome: f32, lr: f32) -> TrainStats {
+        self.train(state, outcome, lr, 0.0, LossWeights::default()).0
+    }
+
+    pub fn train_value(&mut self, state: &Array1<f32>, outcome: f32, lr: f32) -> TrainStats {
+        self.train(state, outcome, 0.0, lr, LossWeights::default()).1
     }
 
-    pub fn train_value(&mut self, state: &Array1<f32>, outcome: f32, lr: f32) {
-        self.value.train(state, &arr1(&[outcome, 1.0 - outcome]), &crate::neural_net::Cost::CrossEntropy, lr);
+    pub fn initializer(&self) -> Option<Initializer> {
+        self.init
+    }
+
+    /// Per-layer weight norms of the policy and value networks. With a trunk, both lists start with its layers.
+    pub fn weight_norms(&self) -> (Vec<f32>, Vec<f32>) {
+        let trunk = self.trunk.as_ref().map_or(vec![], Layer::weight_norms);
+        let path = |head: &Layer| trunk.iter().copied().chain(head.weight_norms()).collect();
+        (path(&self.policy), path(&self.value))
     }
 }
 
 impl Default for Thod {
+    /// The default layer sizes, initialised from `thread_rng`, so every call gives a different network. Use
+    /// `from_shape` with a seeded RNG for a reproducible one.
     fn default() -> Self {
-        Self::from_shape(vec![500, 250, 100], vec![750, 500, 250, 100, 10])
+        Self::from_shape(vec![500, 250, 100], vec![750, 500, 250, 100, 10], Initializer::default(), &mut rand::thread_rng())
     }
 }
 
 impl Tools for Thod {
     fn policy(&self, state: &ndarray::Array1<f32>) -> f32 {
-        let r = self.policy.predict(state);
-        return r[0];
+        let r = self.policy.predict(&self.features(state));
+        r[0]
     }
 
     fn value(&self, state: &ndarray::Array1<f32>) -> f32 {
-        let r = self.value.predict(state);
-        return r[0];
+        let r = self.value.predict(&self.features(state));
+        r[0]
+    }
+}
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn initializer_is_saved_with_the_model() {
+        let init = Initializer { scheme: crate::neural_net::InitScheme::LeCun, zero_biases: true };
+        let thod = Thod::from_shape(vec![4], vec![4], init, &mut StdRng::seed_from_u64(0));
+
+        let mut json = serde_json::to_value(&thod).unwrap();
+        let saved: Thod = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(saved.initializer(), Some(init));
+
+        // Models saved before the initializer was recorded still load.
+        json.as_object_mut().unwrap().remove("init");
+        let legacy: Thod = serde_json::from_value(json).unwrap();
+        assert_eq!(legacy.initializer(), None);
+    }
+
+    #[test]
+    fn trunk_layout_saves_and_trains() {
+        let rng = &mut StdRng::seed_from_u64(0);
+        let towers = Thod::from_shape(vec![4], vec![4], Initializer::default(), rng);
+        assert!(serde_json::to_value(&towers).unwrap().get("trunk").is_none());
+
+        let mut thod = Thod::with_trunk(vec![16, 8], vec![], vec![4], Initializer::default(), rng);
+        let thod_json = serde_json::to_string(&thod).unwrap();
+        assert!(serde_json::from_str::<Thod>(&thod_json).unwrap().has_trunk());
+
+        let state = Array1::from_shape_fn(INPUTS, |i| (i % 3 == 0) as u8 as f32);
+        let (before, _) = thod.train(&state, 1.0, 0.01, 0.01, LossWeights::default());
+        for _ in 0..20 {
+            thod.train(&state, 1.0, 0.01, 0.01, LossWeights::default());
+        }
+        let (after, _) = thod.train(&state, 1.0, 0.01, 0.01, LossWeights::default());
+        assert!(after.loss < before.loss);
+        assert_eq!(thod.weight_norms().0.len(), 3);
+    }
+
+    #[test]
+    fn policy_learns_move_distribution() {
+        let rng = &mut StdRng::seed_from_u64(0);
+        let state = ChessState::default();
+        let e4: Move = "e2e4".parse().unwrap();
+        // Illegal moves in the target are ignored.
+        let target = [(e4, 0.5), ("e7e5".parse().unwrap(), 0.5)];
+
+        for mut thod in [
+            Thod::from_shape(vec![8], vec![4], Initializer::default(), rng),
+            Thod::with_trunk(vec![8], vec![], vec![4], Initializer::default(), rng),
+        ] {
+            let before = thod.predict_moves(&state, &target).unwrap();
+            for _ in 0..10 {
+                thod.train_moves(&state, &target, 0.05);
+            }
+            let after = thod.predict_moves(&state, &target).unwrap();
+
+            assert!(after.loss < before.loss);
+            assert_eq!(after.rank, 0);
+        }
+
+        let black = state.flipped();
+        let thod = Thod::from_shape(vec![8], vec![4], Initializer::default(), rng);
+        assert!(thod.predict_moves(&black, &[(e4, 1.0)]).is_none());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,131 @@
+use std::{fmt::Display, time::Duration};
+
+use cozy_chess_types::Move;
+
+use crate::chess::ChessState;
+
+/// Statistics for a single root move. `q` and `prior` are from the point of view of the side to move at the root.
+#[derive(Debug, Clone)]
+pub struct MoveStats {
+    pub mv: Move,
+    pub visits: usize,
+    pub q: f32,
+    pub prior: f32,
+    pub probability: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub root: ChessState,
+    pub best_move: Option<Move>,
+    pub pv: Vec<Move>,
+    pub moves: Vec<MoveStats>,
+    pub nodes: usize,
+    pub depth: usize,
+    pub seldepth: usize,
+    pub elapsed: Duration,
+}
+
+impl SearchResult {
+    pub fn best(&self) -> Option<&MoveStats> {
+        let mv = self.best_move?;
+        self.moves.iter().find(|x| x.mv == mv)
+    }
+
+    /// Expected score of the best move for the side to move, in [0, 1].
+    pub fn score(&self) -> f32 {
+        self.best().map(|x| x.q).unwrap_or(0.5)
+    }
+
+    pub fn nps(&self) -> f32 {
+        self.nodes as f32 / self.elapsed.as_secs_f32().max(f32::EPSILON)
+    }
+
+    pub fn pv_uci(&self) -> Vec<String> {
+        self.root.uci_line(&self.pv)
+    }
+
+    /// The UCI `bestmove` line, with the second PV move as the ponder move.
+    pub fn bestmove(&self) -> String {
+        let pv = self.pv_uci();
+        match (pv.first(), pv.get(1)) {
+            (Some(best), Some(ponder)) => format!("bestmove {best} ponder {ponder}"),
+            (Some(best), None) => format!("bestmove {best}"),
+            _ => "bestmove 0000".to_owned(),
+        }
+    }
+}
+
+impl Display for SearchResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "info depth {} seldepth {} nodes {} nps {} time {} score cp {} pv {}",
+            self.depth,
+            self.seldepth,
+            self.nodes,
+            self.nps() as u64,
+            self.elapsed.as_millis(),
+            winrate_to_cp(self.score()),
+            self.pv_uci().join(" "),
+        )
+    }
+}
+
+/// One of the top-N lines from a position: the root move's statistics and the line that follows it.
+#[derive(Debug, Clone)]
+pub struct PvLine {
+    pub rank: usize,
+    pub stats: MoveStats,
+    pub pv: Vec<Move>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MultiPv {
+    pub root: ChessState,
+    pub lines: Vec<PvLine>,
+}
+
+impl MultiPv {
+    pub fn best(&self) -> Option<&PvLine> {
+        self.lines.first()
+    }
+}
+
+impl Display for MultiPv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            writeln!(
+                f, "info multipv {} score cp {} visits {} prior {:.3} pv {}",
+                line.rank,
+                winrate_to_cp(line.stats.q),
+                line.stats.visits,
+                line.stats.prior,
+                self.root.uci_line(&line.pv).join(" "),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Maps an expected score onto the usual centipawn scale (50% -> 0cp, ~64% -> 100cp).
+pub fn winrate_to_cp(p: f32) -> i32 {
+    let p = p.clamp(0.001, 0.999);
+    (400.0 * (p / (1.0 - p)).log10()).round() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn winrate_to_cp_matches_the_elo_scale() {
+        assert_eq!(winrate_to_cp(0.5), 0);
+        // 400 * log10(3) for a 75% win rate.
+        assert_eq!(winrate_to_cp(0.75), 191);
+        assert_eq!(winrate_to_cp(0.25), -191);
+        assert_eq!(winrate_to_cp(0.9), 382);
+        // Certain results are clamped to a finite score.
+        assert_eq!(winrate_to_cp(1.0), 1200);
+        assert_eq!(winrate_to_cp(0.0), -1200);
+    }
+}
@@ -0,0 +1,336 @@
+use std::{collections::HashMap, fmt::Display, fs, time::Duration};
+
+use anyhow::Result;
+use cozy_chess::GameStatus;
+use cozy_chess_types::{Color, Move};
+use rand::{distributions::WeightedIndex, prelude::Distribution, seq::SliceRandom, Rng};
+use rand_distr::Dirichlet;
+
+use crate::chess::ChessState;
+
+use super::{adjudication::{AdjudicationConfig, Adjudicator}, search::SearchResult, time::TimeManager, tools::{AccumulativeAnalysis, Tools}};
+
+#[derive(Debug, Clone)]
+pub struct SelfPlayConfig {
+    pub dirichlet_alpha: f32,
+    /// Fraction of each root prior replaced by noise.
+    pub dirichlet_epsilon: f32,
+    pub temperature: f32,
+    /// Plies played at `temperature` before switching to always playing the most visited move.
+    pub temperature_plies: usize,
+    pub move_time: Duration,
+    /// Search a fixed number of nodes per move instead of for `move_time`, making games reproducible from the seed.
+    pub nodes: Option<usize>,
+    pub c: f32,
+    pub adjudication: AdjudicationConfig,
+}
+
+impl SelfPlayConfig {
+    pub fn temperature(&self, ply: usize) -> f32 {
+        if ply < self.temperature_plies { self.temperature } else { 0.0 }
+    }
+}
+
+impl Default for SelfPlayConfig {
+    fn default() -> Self {
+        Self {
+            dirichlet_alpha: 0.3,
+            dirichlet_epsilon: 0.25,
+            temperature: 1.0,
+            temperature_plies: 30,
+            move_time: Duration::from_secs(1),
+            nodes: None,
+            c: 2.0,
+            adjudication: AdjudicationConfig::default(),
+        }
+    }
+}
+
+/// A searched position and the visit distribution over its legal moves, used as a policy target.
+#[derive(Debug, Clone)]
+pub struct PolicySample {
+    pub state: ChessState,
+    pub policy: Vec<(Move, f32)>,
+}
+
+impl PolicySample {
+    pub fn from_result(result: &SearchResult) -> Self {
+        Self {
+            state: result.root.clone(),
+            policy: result.moves.iter().map(|x| (x.mv, x.probability)).collect(),
+        }
+    }
+}
+
+pub fn dirichlet_noise<R: Rng>(n: usize, alpha: f32, rng: &mut R) -> Vec<f32> {
+    if n < 2 { return vec![1.0; n] }
+    Dirichlet::new_with_size(alpha, n)
+        .map(|d| d.sample(rng))
+        .unwrap_or_else(|_| vec![1.0 / n as f32; n])
+}
+
+/// Picks a root move with probability proportional to `visits^(1 / temperature)`; a temperature of 0 picks the most visited.
+pub fn select_move<R: Rng>(result: &SearchResult, temperature: f32, rng: &mut R) -> Option<Move> {
+    if temperature <= f32::EPSILON { return result.best_move }
+
+    // Scale by the most visited move first so that `powf` stays in [0, 1] instead of overflowing at low temperatures.
+    let max = result.moves.iter().map(|x| x.visits).max().unwrap_or(0).max(1) as f32;
+    let weights: Vec<f32> = result.moves.iter()
+        .map(|x| (x.visits as f32 / max).powf(1.0 / temperature))
+        .collect();
+
+    match WeightedIndex::new(&weights) {
+        Ok(dist) => Some(result.moves[dist.sample(rng)].mv),
+        Err(_) => result.best_move,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameOutcome {
+    WhiteWin,
+    BlackWin,
+    Draw,
+}
+
+impl GameOutcome {
+    pub fn from_state(state: &ChessState) -> Option<Self> {
+        match state.board.status() {
+            GameStatus::Won => Some(match state.board.side_to_move() {
+                Color::White => Self::BlackWin,
+                Color::Black => Self::WhiteWin,
+            }),
+            GameStatus::Drawn => Some(Self::Draw),
+            GameStatus::Ongoing => None,
+        }
+    }
+
+    /// Score from White's point of view, matching the value network's target.
+    pub fn score(&self) -> f32 {
+        match self {
+            Self::WhiteWin => 1.0,
+            Self::BlackWin => 0.0,
+            Self::Draw => 0.5,
+        }
+    }
+}
+
+impl Display for GameOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::WhiteWin => "1-0",
+            Self::BlackWin => "0-1",
+            Self::Draw => "1/2-1/2",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GameRecord {
+    pub start: ChessState,
+    pub moves: Vec<Move>,
+    pub samples: Vec<PolicySample>,
+    pub outcome: GameOutcome,
+    /// Set when resignation was disabled for this game but would have triggered for this side.
+    pub would_resign: Option<Color>,
+}
+
+impl GameRecord {
+    /// Whether a resignation that was held back would have been wrong, i.e. the side went on to not lose.
+    pub fn false_resignation(&self) -> Option<bool> {
+        self.would_resign.map(|side| match side {
+            Color::White => self.outcome != GameOutcome::BlackWin,
+            Color::Black => self.outcome != GameOutcome::WhiteWin,
+        })
+    }
+
+    /// Every searched position with its visit distribution and the final result as value target.
+    pub fn targets(&self) -> impl Iterator<Item = (&PolicySample, f32)> {
+        let score = self.outcome.score();
+        self.samples.iter().map(move |x| (x, score))
+    }
+}
+
+/// Running count of games played with resignation disabled, checking how often resigning would have been wrong.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResignationStats {
+    /// Games where a held-back resignation would have triggered.
+    pub checked: usize,
+    /// Of those, the games the resigning side went on to not lose.
+    pub false_resignations: usize,
+}
+
+impl ResignationStats {
+    pub fn add(&mut self, game: &GameRecord) {
+        if let Some(wrong) = game.false_resignation() {
+            self.checked += 1;
+            self.false_resignations += wrong as usize;
+        }
+    }
+
+    /// Fraction of checked resignations that would have been wrong, which should stay below a few percent for
+    /// the resignation threshold to be safe.
+    pub fn rate(&self) -> f32 {
+        self.false_resignations as f32 / self.checked.max(1) as f32
+    }
+}
+
+impl Display for ResignationStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} of {} resignations would have been wrong ({:.1}%)", self.false_resignations, self.checked, 100.0 * self.rate())
+    }
+}
+
+pub struct SelfPlay<'a, T: Tools> {
+    tools: &'a T,
+    config: SelfPlayConfig,
+    openings: Vec<ChessState>,
+}
+
+impl<'a, T: Tools> SelfPlay<'a, T> {
+    pub fn new(tools: &'a T, config: SelfPlayConfig) -> Self {
+        Self { tools, config, openings: vec![] }
+    }
+
+    /// Starts each game from a random one of `openings` instead of the initial position.
+    pub fn with_openings(mut self, openings: Vec<ChessState>) -> Self {
+        self.openings = openings;
+        self
+    }
+
+    /// Plays one game to the end, reusing the search tree between moves.
+    pub fn play<R: Rng>(&self, rng: &mut R) -> GameRecord {
+        let start = self.openings.choose(rng).cloned().unwrap_or_default();
+        let mut analysis = AccumulativeAnalysis::from_position(start.clone()).unwrap();
+        let mut state = start.clone();
+        let mut moves = vec![];
+        let mut samples = vec![];
+        let mut repetitions = HashMap::new();
+        let resign = rng.gen::<f32>() >= self.config.adjudication.resign_disabled_fraction;
+        let mut adjudicator = Adjudicator::new(self.config.adjudication.clone(), resign);
+
+        let outcome = loop {
+            if let Some(outcome) = GameOutcome::from_state(&state) { break outcome }
+            if let Some(outcome) = adjudicator.check_position(&state, moves.len()) { break outcome }
+
+            let hash = state.board.hash();
+            let seen = repetitions.entry(hash).or_insert(0);
+            *seen += 1;
+            if *seen >= 3 { break GameOutcome::Draw }
+
+            if analysis.try_get_analysis(&hash).is_none() {
+                analysis = AccumulativeAnalysis::from_position(state.clone()).unwrap();
+            }
+
+            analysis.add_root_noise(hash, self.tools, self.config.dirichlet_alpha, self.config.dirichlet_epsilon, rng);
+            let time = match self.config.nodes {
+                Some(nodes) => TimeManager::nodes(nodes),
+                None => TimeManager::fixed(self.config.move_time),
+            };
+            let result = analysis.search(hash, self.tools, self.config.c, &time).unwrap();
+
+            samples.push(PolicySample::from_result(&result));
+            if let Some(outcome) = adjudicator.update(&state, moves.len(), result.score()) { break outcome }
+
+            let Some(mv) = select_move(&result, self.config.temperature(moves.len()), rng) else {
+                break GameOutcome::Draw
+            };
+
+            moves.push(mv);
+            state.board.play_unchecked(mv);
+        };
+
+        GameRecord { start, moves, samples, outcome, would_resign: adjudicator.would_resign() }
+    }
+}
+
+/// Reads an opening set with one FEN per line; blank lines and lines starting with `#` are skipped.
+pub fn load_openings(path: &str) -> Result<Vec<ChessState>> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(ChessState::from_fen)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use crate::engine::search::MoveStats;
+
+    use super::*;
+
+    fn game(outcome: GameOutcome, would_resign: Option<Color>) -> GameRecord {
+        GameRecord { start: ChessState::default(), moves: vec![], samples: vec![], outcome, would_resign }
+    }
+
+    #[test]
+    fn dirichlet_noise_is_a_distribution() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for (n, alpha) in [(2, 0.3), (20, 0.3), (20, 0.03), (218, 0.3), (30, 10.0)] {
+            let noise = dirichlet_noise(n, alpha, &mut rng);
+            assert_eq!(noise.len(), n);
+            assert!(noise.iter().all(|x| (0.0..=1.0).contains(x)));
+            assert!((noise.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+        }
+
+        assert_eq!(dirichlet_noise(1, 0.3, &mut rng), vec![1.0]);
+        assert!(dirichlet_noise(0, 0.3, &mut rng).is_empty());
+    }
+
+    fn searched(visits: &[usize]) -> SearchResult {
+        let root = ChessState::default();
+        let mut legal = vec![];
+        root.board.generate_moves(|x| { legal.extend(x); false });
+        let moves: Vec<_> = visits.iter().zip(legal)
+            .map(|(&visits, mv)| MoveStats { mv, visits, q: 0.5, prior: 0.0, probability: 0.0 })
+            .collect();
+        let best_move = moves.iter().max_by_key(|x| x.visits).map(|x| x.mv);
+        SearchResult {
+            root, best_move, pv: best_move.into_iter().collect(), moves,
+            nodes: visits.iter().sum(), depth: 0, seldepth: 0, elapsed: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn zero_temperature_plays_the_most_visited_move() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let result = searched(&[10, 500, 30]);
+        for _ in 0..20 {
+            assert_eq!(select_move(&result, 0.0, &mut rng), Some(result.moves[1].mv));
+        }
+    }
+
+    #[test]
+    fn low_temperature_with_many_visits_does_not_overflow() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let result = searched(&[400_000, 1_000_000, 3]);
+        for temperature in [1.0, 0.1, 0.01, 1e-3] {
+            assert!(select_move(&result, temperature, &mut rng).is_some());
+        }
+        assert_eq!(select_move(&result, 0.01, &mut rng), Some(result.moves[1].mv));
+    }
+
+    #[test]
+    fn temperature_drops_to_zero_after_the_opening_plies() {
+        let config = SelfPlayConfig { temperature: 0.8, temperature_plies: 4, ..Default::default() };
+        assert_eq!(config.temperature(0), 0.8);
+        assert_eq!(config.temperature(3), 0.8);
+        assert_eq!(config.temperature(4), 0.0);
+        assert_eq!(config.temperature(100), 0.0);
+    }
+
+    #[test]
+    fn resignation_stats_count_wrong_resignations() {
+        let mut stats = ResignationStats::default();
+        stats.add(&game(GameOutcome::WhiteWin, None));
+        stats.add(&game(GameOutcome::BlackWin, Some(Color::White)));
+        stats.add(&game(GameOutcome::Draw, Some(Color::White)));
+        stats.add(&game(GameOutcome::BlackWin, Some(Color::Black)));
+        stats.add(&game(GameOutcome::WhiteWin, Some(Color::Black)));
+
+        assert_eq!((stats.checked, stats.false_resignations), (4, 2));
+        assert_eq!(stats.rate(), 0.5);
+    }
+}
@@ -0,0 +1,141 @@
+use std::time::{Duration, Instant};
+
+/// Assumed number of moves left in the game when the clock has no `moves_to_go`.
+const DEFAULT_MOVES_TO_GO: u32 = 30;
+/// Kept back from every budget to cover move transmission / bookkeeping.
+const MOVE_OVERHEAD: Duration = Duration::from_millis(50);
+/// Past the soft limit, keep searching while the runner-up is within this fraction of the best.
+const INSTABILITY_MARGIN: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimeControl {
+    pub remaining: Duration,
+    pub increment: Duration,
+    pub moves_to_go: Option<u32>,
+}
+
+impl TimeControl {
+    pub fn new(remaining: Duration, increment: Duration, moves_to_go: Option<u32>) -> Self {
+        Self { remaining, increment, moves_to_go }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TimeManager {
+    start: Instant,
+    soft: Duration,
+    hard: Duration,
+    nodes: Option<usize>,
+}
+
+impl TimeManager {
+    /// Splits the clock into a soft limit (normal stopping point) and a hard limit (never exceeded).
+    pub fn new(tc: &TimeControl) -> Self {
+        let mtg = tc.moves_to_go.unwrap_or(DEFAULT_MOVES_TO_GO).max(1);
+        let usable = tc.remaining.saturating_sub(MOVE_OVERHEAD);
+
+        let hard = (usable / mtg * 3 + tc.increment).min(usable);
+        let soft = (usable / mtg + tc.increment * 3 / 4).min(hard);
+
+        Self::with_limits(soft, hard)
+    }
+
+    pub fn fixed(movetime: Duration) -> Self {
+        Self::with_limits(movetime, movetime)
+    }
+
+    pub fn with_limits(soft: Duration, hard: Duration) -> Self {
+        Self { start: Instant::now(), soft, hard: hard.max(soft), nodes: None }
+    }
+
+    /// Stops after exactly `nodes` iterations regardless of time, so the same tree and seed give the same search.
+    pub fn nodes(nodes: usize) -> Self {
+        Self { nodes: Some(nodes), ..Self::with_limits(Duration::MAX, Duration::MAX) }
+    }
+
+    pub fn restart(&mut self) {
+        self.start = Instant::now();
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    pub fn soft_limit(&self) -> Duration {
+        self.soft
+    }
+
+    pub fn hard_limit(&self) -> Duration {
+        self.hard
+    }
+
+    /// Decides whether a search that has run `iterations` times should stop, given the
+    /// visit counts of the root's children.
+    pub fn should_stop(&self, iterations: usize, visits: &[usize]) -> bool {
+        if visits.len() <= 1 { return true }
+        if let Some(nodes) = self.nodes { return iterations >= nodes }
+
+        let elapsed = self.elapsed();
+        if elapsed >= self.hard { return true }
+
+        let (best, second) = visits.iter().fold((0, 0), |(b, s), &v| {
+            if v > b { (v, b) } else { (b, s.max(v)) }
+        });
+
+        // Estimate how many more iterations fit before the hard limit; if the runner-up
+        // couldn't catch up even by taking all of them, the choice is already made.
+        let left = (self.hard - elapsed).as_secs_f32();
+        let rate = iterations as f32 / elapsed.as_secs_f32().max(f32::EPSILON);
+        if (best - second) as f32 > rate * left { return true }
+
+        elapsed >= self.soft && (second as f32) < best as f32 * (1.0 - INSTABILITY_MARGIN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A manager with the given limits that started `ago` seconds in the past.
+    fn started(soft: f32, hard: f32, ago: f32) -> TimeManager {
+        let mut tm = TimeManager::with_limits(Duration::from_secs_f32(soft), Duration::from_secs_f32(hard));
+        tm.start = Instant::now() - Duration::from_secs_f32(ago);
+        tm
+    }
+
+    #[test]
+    fn single_legal_move_stops_at_once() {
+        let tm = started(10.0, 20.0, 0.0);
+        assert!(tm.should_stop(0, &[]));
+        assert!(tm.should_stop(0, &[0]));
+        assert!(!tm.should_stop(0, &[0, 0]));
+    }
+
+    #[test]
+    fn hard_limit_always_stops() {
+        // Close visits would keep the search going past the soft limit, but not the hard one.
+        assert!(!started(1.0, 2.0, 1.5).should_stop(200, &[100, 95]));
+        assert!(started(1.0, 2.0, 2.5).should_stop(200, &[100, 95]));
+    }
+
+    #[test]
+    fn soft_limit_stops_once_the_best_move_is_clear() {
+        assert!(!started(1.0, 10.0, 0.5).should_stop(150, &[100, 50]));
+        assert!(started(1.0, 10.0, 1.5).should_stop(150, &[100, 50]));
+        assert!(!started(1.0, 10.0, 1.5).should_stop(195, &[100, 95]));
+    }
+
+    #[test]
+    fn unassailable_lead_stops_early() {
+        // About 1100 iterations a second with half a second left: a lead above ~550 can't be caught.
+        assert!(started(1.4, 1.5, 1.0).should_stop(1100, &[1095, 5]));
+        assert!(!started(1.4, 1.5, 1.0).should_stop(1100, &[600, 500]));
+    }
+
+    #[test]
+    fn node_limit_ignores_time() {
+        let tm = TimeManager::nodes(10);
+        assert!(!tm.should_stop(9, &[5, 4]));
+        assert!(tm.should_stop(10, &[5, 5]));
+    }
+}
@@ -1,22 +1,55 @@
-use std::{cell::{RefCell, RefMut}, collections::{HashMap, VecDeque}, rc::Rc, string::ParseError};
+use std::{cell::{RefCell, RefMut}, collections::{HashMap, HashSet, VecDeque}, rc::Rc};
 
 use anyhow::Result;
 use cozy_chess::Board;
 use cozy_chess_types::{Color, Square, Move};
 use ndarray::Array1;
-use rand::{seq::IteratorRandom, rngs::ThreadRng};
+use rand::{seq::SliceRandom, Rng};
 
-use crate::{chess::ChessState, game::Game};
+use crate::{chess::ChessState, game::Game, neural_net::{logit, softmax}};
+
+use super::{search::{MoveStats, MultiPv, PvLine, SearchResult}, selfplay::dirichlet_noise, time::TimeManager};
 
 pub trait Tools {
     fn policy(&self, state: &Array1<f32>) -> f32;
     fn value(&self, state: &Array1<f32>) -> f32;
 }
 
-enum GamePrediction {
-    Win,
-    Draw,
-    Loss,
+/// Scores positions with noise derived from the position and `seed`, so the same seed always plays the same
+/// way no matter how many threads share it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandTool {
+    pub seed: u64,
+}
+
+impl RandTool {
+    pub fn new(seed: u64) -> Self {
+        Self { seed }
+    }
+
+    fn noise(&self, state: &Array1<f32>, salt: u64) -> f32 {
+        let h = state.iter().enumerate()
+            .filter(|(_, x)| **x != 0.0)
+            .fold(splitmix64(self.seed ^ salt), |h, (i, x)| splitmix64(h ^ ((i as u64) << 32 | x.to_bits() as u64)));
+        (h >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+impl Tools for RandTool {
+    fn policy(&self, state: &Array1<f32>) -> f32 {
+        self.noise(state, 0)
+    }
+
+    fn value(&self, state: &Array1<f32>) -> f32 {
+        self.noise(state, 1)
+    }
 }
 
 #[derive(Debug)]
@@ -81,8 +114,8 @@ impl Analysis {
 
     fn exploit(&self) -> f32 {
         match self.state.board.side_to_move() {
-            Color::Black => 1.0 - (self.wins as f32 / self.visits as f32),
-            Color::White => self.wins as f32 / self.visits as f32,
+            Color::Black => 1.0 - (self.wins / self.visits as f32),
+            Color::White => self.wins / self.visits as f32,
         }
     }
 
@@ -94,7 +127,7 @@ impl Analysis {
         self.children.iter_mut()
             .map(|x| x.analysis())
             .map(|x| (x.ucb(self.visits, c), x))
-            .max_by(|a, b| a.0.partial_cmp(&b.0).or(Some(std::cmp::Ordering::Equal)).unwrap())
+            .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal))
             .unwrap().1
     }
 
@@ -103,7 +136,7 @@ impl Analysis {
             .map(|x| x.analysis())
             .map(|x| (x.ucb(self.visits, 0.0), x))
             .enumerate()
-            .max_by(|(_, a), (_, b)| a.0.partial_cmp(&b.0).or(Some(std::cmp::Ordering::Equal)).unwrap())
+            .max_by(|(_, a), (_, b)| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal))
             .unwrap();
 
         *self = self.children.remove(idx).take();
@@ -122,7 +155,7 @@ impl Analysis {
     pub fn show_board(&self) {
         for (i, square) in Square::ALL.into_iter().enumerate() {
             if let Some(peice) =  self.state.board.piece_on(square) {
-                print!(" {}{} ", peice.to_string(), match self.state.board.color_on(square).unwrap() {
+                print!(" {}{} ", peice, match self.state.board.color_on(square).unwrap() {
                     Color::White => 'w',
                     Color::Black => 'b',
                 });
@@ -130,7 +163,7 @@ impl Analysis {
             if i & 0x7 == 7 { println!() }
         }
 
-        println!("{}", self.state.board.to_string());
+        println!("{}", self.state.board);
     }
 
     pub fn simulate<T: Tools>(&mut self, tools: &T, depth: usize) -> f32 {
@@ -175,12 +208,13 @@ impl Analysis {
 
 pub struct AccumulativeAnalysis {
     positions: HashMap<u64, Rc<RefCell<PositionAnalysis>>>, // pain
-    temp_pool: HashMap<u64, ChessState>
+    temp_pool: HashMap<u64, ChessState>,
+    noisy_root: Option<u64>,
 }
 
 impl AccumulativeAnalysis {
     fn with_positions(positions: HashMap<u64, Rc<RefCell<PositionAnalysis>>>) -> Self {
-        Self { positions, temp_pool: HashMap::new() }
+        Self { positions, temp_pool: HashMap::new(), noisy_root: None }
     }
 
     pub fn from_position(state: ChessState) -> Result<Self> {
@@ -204,7 +238,7 @@ impl AccumulativeAnalysis {
         self.temp_pool.insert(hash, state);
     }
 
-    pub fn try_get_analysis<'a>(&'a mut self, hash: &u64) -> Option<Rc<RefCell<PositionAnalysis>>> {
+    pub fn try_get_analysis(&mut self, hash: &u64) -> Option<Rc<RefCell<PositionAnalysis>>> {
         if let Some(analysis) = self.positions.get(hash) { return Some(analysis.clone()); }
         else if let Some(state) = self.temp_pool.remove(hash) { 
             let (analysis, children) = PositionAnalysis::from_state(state);
@@ -220,13 +254,91 @@ impl AccumulativeAnalysis {
         None
     } 
 
-    pub fn mcts<T: Tools>(&mut self, hash: u64, tools: &T, c: f32, depth: usize) -> Option<()> {
+    pub fn mcts<T: Tools>(&mut self, hash: u64, tools: &T, c: f32) -> Option<()> {
         if let Some(a) = self.try_get_analysis(&hash) {
-            a.borrow_mut().mcts(self, tools, c, depth);
+            a.borrow_mut().mcts(self, tools, c);
             Some(())
         } else { None }
     }
 
+    /// Runs MCTS iterations from `hash` until `time` says to stop.
+    pub fn search<T: Tools>(&mut self, hash: u64, tools: &T, c: f32, time: &TimeManager) -> Option<SearchResult> {
+        let root = self.try_get_analysis(&hash)?;
+        let mut nodes = 0;
+        let mut total_depth = 0;
+        let mut seldepth = 0;
+
+        loop {
+            let d = root.borrow_mut().mcts(self, tools, c);
+            nodes += 1;
+            total_depth += d;
+            seldepth = seldepth.max(d);
+
+            let visits = root.borrow().child_visits(self);
+            if time.should_stop(nodes, &visits) { break }
+        }
+
+        let root = root.borrow();
+        let moves = root.move_stats(self, tools);
+        let pv = root.principal_variation(self);
+
+        Some(SearchResult {
+            root: root.state(),
+            best_move: pv.first().copied(),
+            pv,
+            moves,
+            nodes,
+            depth: total_depth / nodes,
+            seldepth,
+            elapsed: time.elapsed(),
+        })
+    }
+
+    /// Mixes Dirichlet noise into the move priors at `hash`, which then steer selection there.
+    /// Noise previously added to another root is removed.
+    pub fn add_root_noise<T: Tools, R: Rng>(&mut self, hash: u64, tools: &T, alpha: f32, epsilon: f32, rng: &mut R) -> Option<()> {
+        let root = self.try_get_analysis(&hash)?;
+
+        if let Some(prev) = self.noisy_root.replace(hash) {
+            if let Some(a) = self.positions.get(&prev).filter(|_| prev != hash) {
+                a.borrow_mut().priors = None;
+            }
+        }
+
+        let priors = root.borrow().priors(self, tools);
+        let noise = dirichlet_noise(priors.len(), alpha, rng);
+        root.borrow_mut().priors = Some(
+            priors.iter().zip(noise).map(|(p, n)| (1.0 - epsilon) * p + epsilon * n).collect()
+        );
+
+        Some(())
+    }
+
+    /// The `n` most visited moves from `hash`, each with its own principal variation. Moves that were never
+    /// visited are left out, so there may be fewer than `n` lines.
+    pub fn multi_pv<T: Tools>(&mut self, hash: u64, tools: &T, n: usize) -> Option<MultiPv> {
+        let root = self.try_get_analysis(&hash)?;
+        let root = root.borrow();
+
+        let mut moves: Vec<_> = root.move_stats(self, tools).into_iter().zip(root.children()).collect();
+        moves.sort_by(|(a, _), (b, _)| {
+            b.visits.cmp(&a.visits).then(b.q.partial_cmp(&a.q).unwrap_or(std::cmp::Ordering::Equal))
+        });
+
+        let lines = moves.into_iter()
+            .filter(|(stats, _)| stats.visits > 0)
+            .take(n)
+            .enumerate()
+            .map(|(i, (stats, child))| {
+                let mut pv = vec![stats.mv];
+                pv.extend(self.try_get_analysis(&child).unwrap().borrow().principal_variation(self));
+                PvLine { rank: i + 1, stats, pv }
+            })
+            .collect();
+
+        Some(MultiPv { root: root.state(), lines })
+    }
+
     pub fn training_data<'a>(&'a self, threshold: usize) -> impl Iterator<Item = (f32, Array1<f32>)> + 'a {
         self.positions.iter()
             .filter(move |(_, data)| data.borrow().visits > threshold)
@@ -237,8 +349,21 @@ impl AccumulativeAnalysis {
             })
     }
 
-    pub fn random_hash(&self) -> u64 {
-        *self.positions.keys().choose(&mut ThreadRng::default()).unwrap()
+    /// Like `training_data`, but with the positions themselves rather than their encodings.
+    pub fn training_positions(&self, threshold: usize) -> impl Iterator<Item = (f32, ChessState)> + '_ {
+        self.positions.values()
+            .filter(move |data| data.borrow().visits > threshold)
+            .map(|data| {
+                let d = data.borrow();
+                (d.exploit(), d.state.clone())
+            })
+    }
+
+    /// Sorted first, since the map's own iteration order differs between runs.
+    pub fn random_hash<R: Rng>(&self, rng: &mut R) -> u64 {
+        let mut hashes: Vec<u64> = self.positions.keys().copied().collect();
+        hashes.sort_unstable();
+        *hashes.choose(rng).unwrap()
     }
 
 }
@@ -252,6 +377,7 @@ pub struct PositionAnalysis {
     policy: RefCell<Option<f32>>,
     children: Vec<u64>,
     value: RefCell<Option<f32>>,
+    priors: Option<Vec<f32>>,
 }
 
 impl PositionAnalysis {
@@ -266,6 +392,7 @@ impl PositionAnalysis {
             hash: state.board.hash(),
             policy: RefCell::new(None),
             value: RefCell::new(None),
+            priors: None,
             children: children.iter().map(|x| x.0).collect(),
             state,
         }, children)
@@ -273,18 +400,23 @@ impl PositionAnalysis {
 
     pub fn exploit(&self) -> f32 {
         match self.state.board.side_to_move() {
-            Color::Black => 1.0 - (self.wins as f32 / self.visits as f32),
-            Color::White => self.wins as f32 / self.visits as f32,
+            Color::Black => 1.0 - (self.wins / self.visits as f32),
+            Color::White => self.wins / self.visits as f32,
         }
     }
 
+    /// Win rate for the player who moved into this position.
+    pub fn q(&self) -> f32 {
+        1.0 - self.wins / self.visits as f32
+    }
+
     fn explore(&self, n: usize, c: f32) -> f32 {
         (c * ((n as f32).ln() / self.visits as f32)).sqrt()
     }
 
     pub fn ucb(&self, n: usize, c: f32) -> f32 {
         if !self.visited() || n == 0 { return 100.0 }
-        self.exploit() + self.explore(n, c)
+        self.q() + self.explore(n, c)
     }
 
     pub fn policy<T: Tools>(&self, tools: &T) -> f32 {
@@ -309,15 +441,32 @@ impl PositionAnalysis {
         }
     }
 
+    pub fn visits(&self) -> usize {
+        self.visits
+    }
+
+    pub fn child_visits(&self, cache: &mut AccumulativeAnalysis) -> Vec<usize> {
+        self.children.iter()
+            .map(|x| cache.try_get_analysis(x).unwrap())
+            .map(|x| x.borrow().visits)
+            .collect()
+    }
+
     pub fn visited(&self) -> bool {
         self.visits != 0
     }
 
-    pub fn search<T: Tools>(&self, cache: &mut AccumulativeAnalysis, tools: &T, c: f32, q: &mut VecDeque<Rc<RefCell<PositionAnalysis>>>) -> Rc<RefCell<PositionAnalysis>> {
+    pub fn search(&self, cache: &mut AccumulativeAnalysis, c: f32, q: &mut VecDeque<Rc<RefCell<PositionAnalysis>>>) -> Rc<RefCell<PositionAnalysis>> {
         
         let analysis = self.children.iter()
-            .map(|x| cache.try_get_analysis(x).unwrap())
-            .map(|x| ({x.borrow().ucb(self.visits, c)}, x.clone()))
+            .enumerate()
+            .map(|(i, x)| (i, cache.try_get_analysis(x).unwrap()))
+            // Positions already on the current path are borrowed; skipping them stops transpositions looping.
+            .filter(|(_, x)| x.try_borrow_mut().is_ok())
+            .map(|(i, x)| ({
+                let x = x.borrow();
+                x.ucb(self.visits, c) + self.prior_bonus(i, x.visits, c)
+            }, x.clone()))
             .max_by(|x, y| x.0.partial_cmp(&y.0).unwrap());
 
         if let Some(a) = analysis {
@@ -325,7 +474,7 @@ impl PositionAnalysis {
             if !a.1.borrow().visited() {
                 return a.1;
             } else {
-                return a.1.borrow().search(cache, tools, c, q);
+                return a.1.borrow().search(cache, c, q);
             }
         } 
         
@@ -334,47 +483,12 @@ impl PositionAnalysis {
         a
     }
 
-    pub fn rollout<T: Tools>(&self, cache: &mut AccumulativeAnalysis, tools: &T, depth: usize) -> f32 {
-        
-        
-        if true || depth == 0 {
-            let score = self.value(tools);
-            // self.increment(score);
-            return score;
-        }
-
-        if self.children.len() == 0 {
-            match self.state.board.status() {
-                cozy_chess::GameStatus::Won => return match self.state.board.side_to_move() {
-                    Color::White => 0.0,
-                    Color::Black => 1.0,
-                },
-                cozy_chess::GameStatus::Drawn => return 0.0,
-                _ => panic!(),
-            }
-        }
-
-        if self.visits != 0 {
-            let selected = self.children.iter()
-                .map(|x| cache.try_get_analysis(x).unwrap())
-                .filter(|x| x.borrow().visited())
-                .map(|x| (x.borrow().exploit(), x.clone()))
-                .max_by(|x, y| x.0.partial_cmp(&y.0).unwrap());
-
-            if let Some((_, analysis)) = selected {
-                return analysis.borrow().rollout(cache, tools, depth - 1);
-            }
-
+    /// PUCT-style exploration bonus for child `i`, only present once priors have been set on this position.
+    fn prior_bonus(&self, i: usize, visits: usize, c: f32) -> f32 {
+        match &self.priors {
+            Some(p) => c * p[i] * (self.visits as f32).sqrt() / (1 + visits) as f32,
+            None => 0.0,
         }
-
-        let analysis = self.children.iter()
-            .map(|x| cache.try_get_analysis(x).unwrap())
-            .map(|x| (x.borrow().policy(tools), x.clone()))
-            .max_by(|x, y| x.0.partial_cmp(&y.0).unwrap())
-            .unwrap().1;
-
-        let x = analysis.borrow().rollout(cache, tools, depth - 1); 
-        x
     }
 
     fn side(&self) -> Color {
@@ -389,12 +503,22 @@ impl PositionAnalysis {
         }
     }
 
-    pub fn mcts<T: Tools>(&mut self, cache: &mut AccumulativeAnalysis, tools: &T, c: f32, depth: usize) {
+    /// Runs one MCTS iteration, scoring the selected leaf with the value network, and returns how many plies
+    /// deep the selection went.
+    pub fn mcts<T: Tools>(&mut self, cache: &mut AccumulativeAnalysis, tools: &T, c: f32) -> usize {
         let mut q = VecDeque::default();
-        let analysis = self.search(cache, tools, c, &mut q);
-        let score = analysis.borrow().rollout(cache, tools, depth);
+        let analysis = self.search(cache, c, &mut q);
+        let score = if std::ptr::eq(analysis.as_ptr(), self) {
+            self.value(tools)
+        } else {
+            analysis.borrow().value(tools)
+        };
         self.increment(score);
-        q.drain(..).for_each(|x| x.borrow_mut().increment(score));
+        let depth = q.len();
+        q.drain(..)
+            .filter(|x| !std::ptr::eq(x.as_ptr(), self))
+            .for_each(|x| x.borrow_mut().increment(score));
+        depth
     }
 
     pub fn p(&mut self, cache: &mut AccumulativeAnalysis) -> Vec<f32> {
@@ -404,6 +528,68 @@ impl PositionAnalysis {
             .collect()
     }
 
+    /// Per-move statistics for this position's children, in `moves()` order.
+    pub fn move_stats<T: Tools>(&self, cache: &mut AccumulativeAnalysis, tools: &T) -> Vec<MoveStats> {
+        let children: Vec<_> = self.children.iter()
+            .map(|x| cache.try_get_analysis(x).unwrap())
+            .collect();
+
+        let total = children.iter().map(|x| x.borrow().visits).sum::<usize>().max(1);
+        let priors = self.priors(cache, tools);
+
+        children.iter().zip(self.moves()).zip(priors)
+            .map(|((x, mv), prior)| {
+                let x = x.borrow();
+                MoveStats {
+                    mv,
+                    visits: x.visits,
+                    q: if x.visited() { x.q() } else { 0.5 },
+                    prior,
+                    probability: x.visits as f32 / total as f32,
+                }
+            })
+            .collect()
+    }
+
+    /// Softmax over the logits of the policy network's output for each child, from the side to move's point
+    /// of view. This is the distribution `Thod::train_moves` trains.
+    pub fn priors<T: Tools>(&self, cache: &mut AccumulativeAnalysis, tools: &T) -> Vec<f32> {
+        let logits: Vec<_> = self.children.iter()
+            .map(|x| cache.try_get_analysis(x).unwrap())
+            .map(|x| match self.side() {
+                Color::White => logit(x.borrow().policy(tools)),
+                Color::Black => -logit(x.borrow().policy(tools)),
+            })
+            .collect();
+
+        softmax(&logits)
+    }
+
+    /// Follows the most visited child from this position until reaching an unvisited node or a repetition.
+    pub fn principal_variation(&self, cache: &mut AccumulativeAnalysis) -> Vec<Move> {
+        let mut pv = vec![];
+        let mut seen = HashSet::from([self.hash]);
+        let mut line = (self.moves(), self.children.clone());
+
+        loop {
+            let best = line.1.iter().zip(line.0)
+                .map(|(x, mv)| (cache.try_get_analysis(x).unwrap(), mv))
+                .filter(|(x, _)| x.borrow().visited())
+                .max_by_key(|(x, _)| x.borrow().visits);
+
+            match best {
+                Some((x, mv)) if seen.insert(x.borrow().hash) => {
+                    pv.push(mv);
+                    let x = x.borrow();
+                    line = (x.moves(), x.children.clone());
+                },
+                _ => break,
+            }
+        }
+
+        pv
+    }
+
     pub fn children(&self) -> Vec<u64> {
         self.children.clone()
     }
@@ -415,4 +601,172 @@ impl PositionAnalysis {
     pub fn state(&self) -> ChessState {
         self.state.clone()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    /// Sets the statistics of the position reached by playing `moves` from the root, which must already be
+    /// reachable through expanded positions.
+    fn visit(analysis: &mut AccumulativeAnalysis, moves: &[&str], visits: usize, wins: f32) {
+        let mut state = ChessState::default();
+        for mv in moves {
+            state.board.play(mv.parse().unwrap());
+        }
+        let node = analysis.try_get_analysis(&state.board.hash()).unwrap();
+        let mut node = node.borrow_mut();
+        node.visits = visits;
+        node.wins = wins;
+    }
+
+    fn root_priors(analysis: &AccumulativeAnalysis, hash: u64) -> Option<Vec<f32>> {
+        analysis.positions[&hash].borrow().priors.clone()
+    }
+
+    #[test]
+    fn root_noise_keeps_priors_normalised() {
+        let state = ChessState::default();
+        let hash = state.board.hash();
+        let tools = RandTool::new(0);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let mut analysis = AccumulativeAnalysis::from_position(state).unwrap();
+        let root = analysis.try_get_analysis(&hash).unwrap();
+        let clean = root.borrow().priors(&mut analysis, &tools);
+
+        for epsilon in [0.0, 0.25, 1.0] {
+            analysis.add_root_noise(hash, &tools, 0.3, epsilon, &mut rng).unwrap();
+            let noisy = root_priors(&analysis, hash).unwrap();
+
+            assert_eq!(noisy.len(), 20);
+            assert!(noisy.iter().all(|x| *x >= 0.0));
+            assert!((noisy.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+            // Each prior keeps at least its (1 - epsilon) share of the clean one.
+            assert!(noisy.iter().zip(&clean).all(|(n, c)| *n >= (1.0 - epsilon) * c - 1e-6));
+        }
+
+        // No noise leaves the network's priors untouched.
+        analysis.add_root_noise(hash, &tools, 0.3, 0.0, &mut rng).unwrap();
+        assert!(root_priors(&analysis, hash).unwrap().iter().zip(&clean).all(|(n, c)| (n - c).abs() < 1e-6));
+    }
+
+    #[test]
+    fn principal_variation_follows_the_most_visited_children() {
+        let hash = ChessState::default().board.hash();
+        let mut analysis = AccumulativeAnalysis::from_position(ChessState::default()).unwrap();
+        visit(&mut analysis, &[], 20, 10.0);
+        visit(&mut analysis, &["e2e4"], 12, 5.0);
+        visit(&mut analysis, &["d2d4"], 7, 3.0);
+        visit(&mut analysis, &["e2e4", "c7c5"], 8, 4.0);
+        visit(&mut analysis, &["e2e4", "e7e5"], 3, 1.0);
+        visit(&mut analysis, &["e2e4", "c7c5", "g1f3"], 5, 2.0);
+        // Expanded but never visited, so the line stops before it.
+        visit(&mut analysis, &["e2e4", "c7c5", "g1f3", "d7d6"], 0, 0.0);
+
+        let root = analysis.try_get_analysis(&hash).unwrap();
+        let pv = root.borrow().principal_variation(&mut analysis);
+        assert_eq!(pv.iter().map(Move::to_string).collect::<Vec<_>>(), ["e2e4", "c7c5", "g1f3"]);
+
+        let mut fresh = AccumulativeAnalysis::from_position(ChessState::default()).unwrap();
+        let root = fresh.try_get_analysis(&hash).unwrap();
+        assert!(root.borrow().principal_variation(&mut fresh).is_empty());
+    }
+
+    #[test]
+    fn multi_pv_lists_visited_moves_by_visits() {
+        let hash = ChessState::default().board.hash();
+        let tools = RandTool::new(0);
+        let mut analysis = AccumulativeAnalysis::from_position(ChessState::default()).unwrap();
+        visit(&mut analysis, &[], 20, 10.0);
+        visit(&mut analysis, &["d2d4"], 7, 3.0);
+        visit(&mut analysis, &["e2e4"], 12, 5.0);
+        visit(&mut analysis, &["e2e4", "c7c5"], 8, 4.0);
+        visit(&mut analysis, &["e2e4", "c7c5", "g1f3"], 5, 2.0);
+
+        let multi_pv = analysis.multi_pv(hash, &tools, 5).unwrap();
+        let lines: Vec<_> = multi_pv.lines.iter()
+            .map(|x| (x.rank, x.stats.visits, x.pv.iter().map(Move::to_string).collect::<Vec<_>>().join(" ")))
+            .collect();
+        assert_eq!(lines, [(1, 12, "e2e4 c7c5 g1f3".to_owned()), (2, 7, "d2d4".to_owned())]);
+        assert_eq!(multi_pv.best().unwrap().stats.mv.to_string(), "e2e4");
+
+        // q is 7/12 for e4 and 4/7 for d4.
+        let (e4, d4) = (multi_pv.lines[0].stats.prior, multi_pv.lines[1].stats.prior);
+        assert_eq!(multi_pv.to_string(), format!(
+            "info multipv 1 score cp 58 visits 12 prior {e4:.3} pv e2e4 c7c5 g1f3\n\
+             info multipv 2 score cp 50 visits 7 prior {d4:.3} pv d2d4\n",
+        ));
+
+        assert_eq!(analysis.multi_pv(hash, &tools, 1).unwrap().lines.len(), 1);
+    }
+
+    #[test]
+    fn move_stats_report_visits_and_q_per_move() {
+        let hash = ChessState::default().board.hash();
+        let tools = RandTool::new(0);
+        let mut analysis = AccumulativeAnalysis::from_position(ChessState::default()).unwrap();
+        visit(&mut analysis, &[], 16, 8.0);
+        // Wins are counted for the side to move in the child, so the mover's q is one minus their rate.
+        visit(&mut analysis, &["e2e4"], 10, 3.0);
+        visit(&mut analysis, &["d2d4"], 6, 4.5);
+
+        let root = analysis.try_get_analysis(&hash).unwrap();
+        let stats = root.borrow().move_stats(&mut analysis, &tools);
+        assert_eq!(stats.len(), 20);
+        assert_eq!(stats.iter().map(|x| x.mv).collect::<Vec<_>>(), root.borrow().moves());
+        assert!((stats.iter().map(|x| x.prior).sum::<f32>() - 1.0).abs() < 1e-4);
+        assert!((stats.iter().map(|x| x.probability).sum::<f32>() - 1.0).abs() < 1e-4);
+
+        let find = |mv: &str| stats.iter().find(|x| x.mv.to_string() == mv).unwrap();
+        let (e4, d4, a3) = (find("e2e4"), find("d2d4"), find("a2a3"));
+        assert_eq!((e4.visits, d4.visits, a3.visits), (10, 6, 0));
+        assert!((e4.q - 0.7).abs() < 1e-6);
+        assert!((d4.q - 0.25).abs() < 1e-6);
+        assert_eq!(a3.q, 0.5);
+        assert!((e4.probability - 10.0 / 16.0).abs() < 1e-6);
+        assert_eq!(a3.probability, 0.0);
+    }
+
+    #[test]
+    fn search_survives_transpositions_back_to_the_root() {
+        // With only king moves, lines like Ka2 Ka7 Ka1 Ka8 quickly lead back to positions on the search path.
+        let state = ChessState { board: "k7/8/8/8/8/8/8/K7 w - - 0 1".parse().unwrap() };
+        let hash = state.board.hash();
+        let mut analysis = AccumulativeAnalysis::from_position(state.clone()).unwrap();
+
+        for _ in 0..2000 {
+            analysis.mcts(hash, &RandTool::new(0), 2.0).unwrap();
+        }
+
+        let root = analysis.try_get_analysis(&hash).unwrap();
+        assert_eq!(root.borrow().visits, 2000);
+
+        // The line that returns to the root was searched.
+        let mut cycle = state;
+        for mv in ["a1a2", "a8a7", "a2a1"] {
+            cycle.board.play(mv.parse().unwrap());
+        }
+        assert!(analysis.try_get_analysis(&cycle.board.hash()).unwrap().borrow().visited());
+    }
+
+    #[test]
+    fn ucb_ranks_children_for_the_side_choosing() {
+        let mut analysis = AccumulativeAnalysis::from_position(ChessState::default()).unwrap();
+        // The side to move in each child scores 20% after the first move and 80% after the second.
+        for (moves, wins) in [(vec!["e2e4"], 2.0), (vec!["d2d4"], 8.0), (vec!["e2e4", "e7e5"], 2.0), (vec!["e2e4", "c7c5"], 8.0)] {
+            visit(&mut analysis, &moves, 10, wins);
+        }
+
+        let ucb = |analysis: &mut AccumulativeAnalysis, moves: &[&str]| {
+            let mut state = ChessState::default();
+            moves.iter().for_each(|mv| state.board.play(mv.parse().unwrap()));
+            analysis.try_get_analysis(&state.board.hash()).unwrap().borrow().ucb(20, 0.0)
+        };
+        // White prefers the move that leaves Black on 20%, and Black likewise leaves White on 20%.
+        assert!(ucb(&mut analysis, &["e2e4"]) > ucb(&mut analysis, &["d2d4"]));
+        assert!(ucb(&mut analysis, &["e2e4", "e7e5"]) > ucb(&mut analysis, &["e2e4", "c7c5"]));
+    }
+}
@@ -0,0 +1,57 @@
+use std::env;
+
+use anyhow::{anyhow, bail, Result};
+use chester::database::{init, Split, TargetSmoothing};
+use chester::packed::{export_positions, PackedWriter};
+
+const USAGE: &str = "\
+usage: export <database> <out.bin> [options]
+
+options:
+  --split NAME            train, validation or test (default train)
+  --min-occurrences N     only positions seen more than N times (default 0)";
+
+fn run() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let mut paths = vec![];
+    let mut split = Split::Train;
+    let mut min_occurences = 0;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--split" => split = match value()?.as_str() {
+                "train" => Split::Train,
+                "validation" => Split::Validation,
+                "test" => Split::Test,
+                x => bail!("Unknown split `{x}`"),
+            },
+            "--min-occurrences" => min_occurences = value()?.parse()?,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            },
+            _ => paths.push(arg),
+        }
+    }
+
+    let [database, out] = <[_; 2]>::try_from(paths).map_err(|_| anyhow!(USAGE))?;
+
+    let conn = init(&database)?;
+    let mut writer = PackedWriter::create(&out)?;
+    let export = export_positions(&conn, split, min_occurences, &TargetSmoothing::default(), &mut writer)?;
+    writer.finish()?;
+
+    println!("Exported {} positions to {out}", export.written);
+    if export.skipped() > 0 {
+        eprintln!("Skipped {} invalid rows: {:?}", export.skipped(), export.failed);
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,39 @@
+use std::env;
+
+use anyhow::{bail, Result};
+use chester::{database::init, packed::import_records, pgn::import_pgn};
+
+const USAGE: &str = "usage: import <database> <file.pgn | file.bin>...";
+
+fn run() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let [database, files @ ..] = args.as_slice() else { bail!(USAGE) };
+    if files.is_empty() { bail!(USAGE) }
+
+    let mut conn = init(database)?;
+    for path in files {
+        println!("Importing {path}");
+        if path.ends_with(".bin") {
+            let stats = import_records(&mut conn, path)?;
+            println!("Finished {path}: {} records, {} without counts or a result skipped", stats.imported, stats.skipped);
+            continue;
+        }
+
+        let stats = import_pgn(&mut conn, path, |x| {
+            println!(
+                "  {} games ({} already imported), {} positions, {} errors, {:.0} games/s",
+                x.games, x.skipped, x.positions, x.errors, x.games_per_sec,
+            );
+        })?;
+        println!("Finished {path}: {} games, {} positions, {} errors", stats.games, stats.positions, stats.errors);
+    }
+
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
@@ -3,3 +3,12 @@ pub mod engine;
 pub mod chess;
 pub mod neural_net;
 pub mod database;
+pub mod sampler;
+pub mod training;
+pub mod metrics;
+pub mod model;
+pub mod replay;
+pub mod pipeline;
+pub mod arena;
+pub mod pgn;
+pub mod packed;
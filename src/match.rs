@@ -0,0 +1,129 @@
+use std::{env, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use chester::arena::{play_match, MatchConfig, SearchSettings, Sprt, SprtResult};
+use chester::engine::{adjudication::AdjudicationConfig, ai::Thod, selfplay::load_openings, tools::{RandTool, Tools}};
+use ndarray::Array1;
+
+const USAGE: &str = "\
+usage: match <engine-a> <engine-b> [options]
+
+engines:  random | <network.json>, optionally followed by :movetime=MS,c=C,seed=N
+
+options:
+  --games N          maximum number of games (default 1000)
+  --max-plies N      adjudicate a draw after N plies (default 512)
+  --no-adjudication  only end games by the rules of chess
+  --threads N        games played in parallel (default 4)
+  --openings FILE    FEN opening set, each opening is played with both colours
+  --sprt ELO0 ELO1   stop as soon as the SPRT accepts either hypothesis
+  --alpha A          SPRT type I error (default 0.05)
+  --beta B           SPRT type II error (default 0.05)";
+
+#[derive(Debug, Clone)]
+enum Player {
+    Network(Box<Thod>),
+    Random(RandTool),
+}
+
+impl Tools for Player {
+    fn policy(&self, state: &Array1<f32>) -> f32 {
+        match self {
+            Self::Network(x) => x.policy(state),
+            Self::Random(x) => x.policy(state),
+        }
+    }
+
+    fn value(&self, state: &Array1<f32>) -> f32 {
+        match self {
+            Self::Network(x) => x.value(state),
+            Self::Random(x) => x.value(state),
+        }
+    }
+}
+
+fn parse_engine(spec: &str) -> Result<(Player, SearchSettings)> {
+    let (name, options) = spec.split_once(':').unwrap_or((spec, ""));
+    let mut player = match name {
+        "random" => Player::Random(RandTool::default()),
+        path => Player::Network(Box::new(Thod::from_file(path)?)),
+    };
+
+    let mut settings = SearchSettings::default();
+    for option in options.split(',').filter(|x| !x.is_empty()) {
+        let (key, value) = option.split_once('=').ok_or_else(|| anyhow!("Expected key=value, got `{option}`"))?;
+        match key {
+            "seed" => match &mut player {
+                Player::Random(x) => x.seed = value.parse()?,
+                Player::Network(_) => bail!("Only the random engine takes a seed"),
+            },
+            "movetime" => settings.move_time = Duration::from_millis(value.parse()?),
+            "c" => settings.c = value.parse()?,
+            _ => bail!("Unknown engine option `{key}`"),
+        }
+    }
+
+    Ok((player, settings))
+}
+
+fn run() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let mut engines = vec![];
+    let mut games = 1000;
+    let mut threads = 4;
+    let mut adjudication = AdjudicationConfig::default();
+    let mut openings = vec![];
+    let mut sprt: Option<Sprt> = None;
+    let (mut alpha, mut beta) = (0.05, 0.05);
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--games" => games = value()?.parse()?,
+            "--threads" => threads = value()?.parse()?,
+            "--max-plies" => adjudication.max_plies = Some(value()?.parse()?),
+            "--no-adjudication" => adjudication = AdjudicationConfig::none(),
+            "--openings" => openings = load_openings(&value()?)?,
+            "--sprt" => sprt = Some(Sprt { elo0: value()?.parse()?, elo1: value()?.parse()?, ..Default::default() }),
+            "--alpha" => alpha = value()?.parse()?,
+            "--beta" => beta = value()?.parse()?,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            },
+            _ => engines.push(parse_engine(&arg)?),
+        }
+    }
+
+    let [a, b] = <[_; 2]>::try_from(engines).map_err(|_| anyhow!("Expected exactly two engines\n\n{USAGE}"))?;
+    let sprt = sprt.map(|x| Sprt { alpha, beta, ..x });
+
+    let stats = play_match(
+        (&a.0, &a.1),
+        (&b.0, &b.1),
+        &openings,
+        &MatchConfig { games, threads, adjudication },
+        |stats| sprt.is_some_and(|x| x.test(stats) != SprtResult::Continue),
+        |i, stats| match sprt {
+            Some(x) => {
+                let (lower, upper) = x.bounds();
+                println!("Game {}: {stats} llr {:.2} ({lower:.2}, {upper:.2})", i + 1, x.llr(stats));
+            },
+            None => println!("Game {}: {stats}", i + 1),
+        },
+    );
+
+    println!("Final: {stats}");
+    if let Some(x) = sprt {
+        println!("SPRT [{}, {}]: {:?}", x.elo0, x.elo1, x.test(&stats));
+    }
+
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,238 @@
+use std::{collections::BTreeMap, fmt::Display, fs::{File, OpenOptions}, io::{BufRead, BufReader, BufWriter, Write}};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Fraction of positions where the target's most likely move is the policy's favourite, or among its
+/// three favourites.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct MoveAccuracy {
+    pub top1: f32,
+    pub top3: f32,
+}
+
+/// Losses and winner accuracy of both heads on a set of positions.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Evaluation {
+    pub policy_loss: f32,
+    pub value_loss: f32,
+    /// Fraction of positions where the head's favourite matches the side the target favours.
+    pub policy_accuracy: f32,
+    pub value_accuracy: f32,
+    /// Set when the policy was evaluated against move targets.
+    #[serde(default)]
+    pub moves: Option<MoveAccuracy>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepMetrics {
+    pub batch: usize,
+    pub epoch: usize,
+    pub lr: f32,
+    /// Mean training loss over the batch, before the update.
+    pub policy_loss: f32,
+    pub value_loss: f32,
+    /// Mean per-sample gradient norm over the batch.
+    pub policy_grad_norm: f32,
+    pub value_grad_norm: f32,
+    pub samples_per_sec: f32,
+    /// Set on the steps that were validated.
+    pub validation: Option<Evaluation>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EpochMetrics {
+    pub epoch: usize,
+    pub batches: usize,
+    pub policy_loss: f32,
+    pub value_loss: f32,
+    pub validation: Evaluation,
+    pub policy_weight_norms: Vec<f32>,
+    pub value_weight_norms: Vec<f32>,
+    pub samples_per_sec: f32,
+}
+
+/// One line of a metrics log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Metric {
+    Step(StepMetrics),
+    Epoch(EpochMetrics),
+    /// The test set, evaluated once at the end of a run.
+    Test(Evaluation),
+}
+
+/// Writes metrics as JSON lines, flushing after each so a running log can be followed.
+pub struct MetricsLog {
+    file: BufWriter<File>,
+}
+
+impl MetricsLog {
+    /// Appends to the log at `path`, so a resumed run keeps the history of the runs before it.
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: BufWriter::new(file) })
+    }
+
+    pub fn log(&mut self, metric: &Metric) -> Result<()> {
+        serde_json::to_writer(&mut self.file, metric)?;
+        self.file.write_all(b"\n")?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+pub fn read_metrics(path: &str) -> Result<Vec<Metric>> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .filter(|x| x.as_ref().map_or(true, |x| !x.trim().is_empty()))
+        .map(|x| Ok(serde_json::from_str(&x?)?))
+        .collect()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RunSummary {
+    pub steps: usize,
+    pub epochs: Vec<EpochMetrics>,
+    pub last_step: Option<StepMetrics>,
+    /// Epoch with the lowest validation value loss.
+    pub best_epoch: Option<usize>,
+    pub mean_samples_per_sec: f32,
+    /// The last test set evaluation in the log.
+    pub test: Option<Evaluation>,
+}
+
+impl RunSummary {
+    pub fn from_metrics(metrics: &[Metric]) -> Self {
+        let mut summary = Self::default();
+        let mut speed = 0.0;
+
+        for metric in metrics {
+            match metric {
+                Metric::Step(x) => {
+                    summary.steps += 1;
+                    speed += x.samples_per_sec;
+                    summary.last_step = Some(x.clone());
+                },
+                Metric::Epoch(x) => summary.epochs.push(x.clone()),
+                Metric::Test(x) => summary.test = Some(*x),
+            }
+        }
+
+        summary.mean_samples_per_sec = speed / summary.steps.max(1) as f32;
+        summary.best_epoch = summary.epochs.iter()
+            .min_by(|a, b| a.validation.value_loss.total_cmp(&b.validation.value_loss))
+            .map(|x| x.epoch);
+        summary
+    }
+
+    /// Epochs keyed by their number. A log appended to by resumed runs can hold an epoch more than once, in
+    /// which case the latest entry wins.
+    pub fn epochs_by_number(&self) -> BTreeMap<usize, &EpochMetrics> {
+        self.epochs.iter().map(|x| (x.epoch, x)).collect()
+    }
+
+    pub fn last_validation(&self) -> Option<Evaluation> {
+        self.epochs.last().map(|x| x.validation)
+            .or_else(|| self.last_step.as_ref().and_then(|x| x.validation))
+    }
+}
+
+impl Display for RunSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} steps, {} epochs, {:.0} samples/s", self.steps, self.epochs.len(), self.mean_samples_per_sec)?;
+        if let Some(x) = &self.last_step {
+            writeln!(f, "last step: lr {} grad norm policy {:.4} value {:.4}", x.lr, x.policy_grad_norm, x.value_grad_norm)?;
+        }
+
+        for x in &self.epochs {
+            write!(
+                f, "epoch {:>3}: train {:.4} / {:.4}  validation {:.4} / {:.4}  accuracy {:.1}% / {:.1}%",
+                x.epoch, x.policy_loss, x.value_loss, x.validation.policy_loss, x.validation.value_loss,
+                100.0 * x.validation.policy_accuracy, 100.0 * x.validation.value_accuracy,
+            )?;
+            if let Some(moves) = x.validation.moves {
+                write!(f, "  moves top-1 {:.1}% top-3 {:.1}%", 100.0 * moves.top1, 100.0 * moves.top3)?;
+            }
+            writeln!(f)?;
+        }
+
+        if let Some(best) = self.best_epoch {
+            writeln!(f, "best validation value loss at epoch {best}")?;
+        }
+        if let Some(x) = &self.test {
+            writeln!(
+                f, "test: {:.4} / {:.4}  accuracy {:.1}% / {:.1}%",
+                x.policy_loss, x.value_loss, 100.0 * x.policy_accuracy, 100.0 * x.value_accuracy,
+            )?;
+        }
+        if let Some(x) = self.epochs.last() {
+            let norms = |v: &[f32]| v.iter().map(|x| format!("{x:.2}")).collect::<Vec<_>>().join(" ");
+            writeln!(f, "weight norms policy [{}] value [{}]", norms(&x.policy_weight_norms), norms(&x.value_weight_norms))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn epoch(epoch: usize, value_loss: f32) -> Metric {
+        Metric::Epoch(EpochMetrics {
+            epoch,
+            batches: 1,
+            policy_loss: 1.0,
+            value_loss: 1.0,
+            validation: Evaluation { value_loss, ..Default::default() },
+            policy_weight_norms: vec![1.0],
+            value_weight_norms: vec![1.0],
+            samples_per_sec: 1.0,
+        })
+    }
+
+    #[test]
+    fn summary_picks_best_epoch_from_logged_lines() {
+        let lines = [epoch(0, 0.9), epoch(1, 0.4), epoch(2, 0.6)]
+            .iter()
+            .map(|x| serde_json::to_string(x).unwrap())
+            .collect::<Vec<_>>();
+        let metrics = lines.iter().map(|x| serde_json::from_str(x).unwrap()).collect::<Vec<Metric>>();
+
+        let summary = RunSummary::from_metrics(&metrics);
+        assert_eq!(summary.epochs.len(), 3);
+        assert!(summary.test.is_none());
+        assert_eq!(summary.best_epoch, Some(1));
+        assert_eq!(summary.last_validation().unwrap().value_loss, 0.6);
+    }
+
+    #[test]
+    fn summary_keeps_the_last_test_evaluation() {
+        let test = |value_loss| Metric::Test(Evaluation { value_loss, ..Default::default() });
+        let summary = RunSummary::from_metrics(&[epoch(0, 0.9), test(0.8), epoch(0, 0.7), test(0.6)]);
+        assert_eq!(summary.test.unwrap().value_loss, 0.6);
+        assert!(summary.to_string().contains("test: 0.0000 / 0.6000"));
+    }
+
+    #[test]
+    fn epochs_are_keyed_by_number() {
+        let summary = RunSummary::from_metrics(&[epoch(0, 0.9), epoch(1, 0.8), epoch(1, 0.7), epoch(2, 0.6)]);
+        let epochs = summary.epochs_by_number();
+        assert_eq!(epochs.keys().copied().collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(epochs[&1].validation.value_loss, 0.7);
+    }
+
+    #[test]
+    fn reopening_a_log_appends() {
+        let path = std::env::temp_dir().join(format!("chester-metrics-{}.jsonl", std::process::id()));
+        let path = path.to_string_lossy();
+        for value_loss in [0.9, 0.5] {
+            MetricsLog::open(&path).unwrap().log(&epoch(0, value_loss)).unwrap();
+        }
+
+        let summary = RunSummary::from_metrics(&read_metrics(&path).unwrap());
+        std::fs::remove_file(&*path).unwrap();
+        assert_eq!(summary.epochs.len(), 2);
+        assert_eq!(summary.last_validation().unwrap().value_loss, 0.5);
+    }
+}
@@ -0,0 +1,53 @@
+use std::{collections::{BTreeMap, BTreeSet}, env};
+
+use anyhow::{bail, Result};
+use chester::metrics::{read_metrics, EpochMetrics, RunSummary};
+
+const USAGE: &str = "\
+usage: metrics summary <run.metrics.jsonl>
+       metrics compare <a.metrics.jsonl> <b.metrics.jsonl>";
+
+fn summarise(path: &str) -> Result<RunSummary> {
+    Ok(RunSummary::from_metrics(&read_metrics(path)?))
+}
+
+fn compare(a: &RunSummary, b: &RunSummary) {
+    println!("{:>5}  {:>21}  {:>21}", "epoch", "a policy / value", "b policy / value");
+    let (a_epochs, b_epochs) = (a.epochs_by_number(), b.epochs_by_number());
+    let epochs = a_epochs.keys().chain(b_epochs.keys()).copied().collect::<BTreeSet<_>>();
+    for i in epochs {
+        let column = |x: &BTreeMap<usize, &EpochMetrics>| match x.get(&i) {
+            Some(x) => format!("{:.4} / {:.4}", x.validation.policy_loss, x.validation.value_loss),
+            None => "-".into(),
+        };
+        println!("{i:>5}  {:>21}  {:>21}", column(&a_epochs), column(&b_epochs));
+    }
+
+    let (Some(x), Some(y)) = (a.last_validation(), b.last_validation()) else { return };
+    println!(
+        "final b - a: policy loss {:+.4} value loss {:+.4} policy accuracy {:+.1}% value accuracy {:+.1}%",
+        y.policy_loss - x.policy_loss,
+        y.value_loss - x.value_loss,
+        100.0 * (y.policy_accuracy - x.policy_accuracy),
+        100.0 * (y.value_accuracy - x.value_accuracy),
+    );
+    println!("speed: a {:.0} samples/s, b {:.0} samples/s", a.mean_samples_per_sec, b.mean_samples_per_sec);
+}
+
+fn run() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["summary", path] => print!("{}", summarise(path)?),
+        ["compare", a, b] => compare(&summarise(a)?, &summarise(b)?),
+        ["-h" | "--help"] => println!("{USAGE}"),
+        _ => bail!(USAGE),
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
@@ -1,10 +1,11 @@
 use std::cell::RefCell;
 
 use ndarray::{Array2, Array1, array, s, NewAxis, arr1};
+use anyhow::{bail, Error};
+use rand::Rng;
+use rand_distr::{Distribution, Normal};
 use serde::{Deserialize, Serialize};
 
-static LN_HALF: f32 = -0.69314718056;
-
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum Activation {
     Linear,
@@ -70,11 +71,128 @@ impl Cost {
         match self {
             Self::Mse => p - y,
             Self::CrossEntropy => p - y,
-            // (y * LN_HALF) / p,
+            // (y * -LN_2) / p,
+        }
+    }
+}
+
+/// How a layer's starting weights are drawn. The normal schemes scale the variance by the layer's fan-in and
+/// fan-out so activations keep a similar magnitude through the network.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InitScheme {
+    /// Picks per layer from its activation with `for_activation`.
+    #[default]
+    Auto,
+    /// Variance 2 / fan-in, for ReLU-like activations.
+    He,
+    /// Variance 2 / (fan-in + fan-out), also known as Glorot.
+    Xavier,
+    /// Variance 1 / fan-in.
+    LeCun,
+    /// Uniform on [-0.5, 0.5] regardless of layer size, as older networks were made.
+    Uniform,
+}
+
+impl InitScheme {
+    pub fn for_activation(activation: &Activation) -> Self {
+        match activation {
+            Activation::ReLU | Activation::LeakyReLU => Self::He,
+            Activation::Linear => Self::LeCun,
+            Activation::Softmax => Self::Xavier,
+        }
+    }
+
+    /// Standard deviation of the normal schemes, `None` for `Uniform`.
+    fn std_dev(&self, inputs: usize, outputs: usize, activation: &Activation) -> Option<f32> {
+        let (fan_in, fan_out) = (inputs.max(1) as f32, outputs.max(1) as f32);
+        match self {
+            Self::Auto => Self::for_activation(activation).std_dev(inputs, outputs, activation),
+            // The gain accounts for the negative slope of leaky units.
+            Self::He => match activation {
+                Activation::LeakyReLU => Some((2.0 / (1.0 + 0.1f32.powi(2)) / fan_in).sqrt()),
+                _ => Some((2.0 / fan_in).sqrt()),
+            },
+            Self::Xavier => Some((2.0 / (fan_in + fan_out)).sqrt()),
+            Self::LeCun => Some((1.0 / fan_in).sqrt()),
+            Self::Uniform => None,
         }
     }
 }
 
+impl std::str::FromStr for InitScheme {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => Self::Auto,
+            "he" => Self::He,
+            "xavier" | "glorot" => Self::Xavier,
+            "lecun" => Self::LeCun,
+            "uniform" => Self::Uniform,
+            _ => bail!("Unknown initialiser `{s}`, expected auto, he, xavier, lecun or uniform"),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct Initializer {
+    pub scheme: InitScheme,
+    /// Start every bias at zero instead of drawing it like the weights.
+    pub zero_biases: bool,
+}
+
+impl Initializer {
+    /// Weights and biases for a layer, with biases drawn like the weights unless `zero_biases` is set.
+    fn layer<R: Rng>(&self, inputs: usize, outputs: usize, activation: &Activation, rng: &mut R) -> (Array2<f32>, Array1<f32>) {
+        let normal = self.scheme.std_dev(inputs, outputs, activation).map(|x| Normal::new(0.0, x).unwrap());
+        let mut draw = || match &normal {
+            Some(normal) => normal.sample(rng),
+            None => rng.gen::<f32>() - 0.5,
+        };
+
+        let weights = Array2::from_shape_simple_fn((outputs, inputs), &mut draw);
+        let biases = match self.zero_biases {
+            true => Array1::zeros(outputs),
+            false => Array1::from_shape_simple_fn(outputs, draw),
+        };
+        (weights, biases)
+    }
+}
+
+/// Softmax over a slice of logits, shifted by their maximum to stay finite.
+pub fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exp: Vec<f32> = logits.iter().map(|x| (x - max).exp()).collect();
+    let total = exp.iter().sum::<f32>();
+    exp.iter().map(|x| x / total).collect()
+}
+
+/// Inverse of the logistic function, with `p` kept away from 0 and 1.
+pub fn logit(p: f32) -> f32 {
+    let p = p.clamp(1e-6, 1.0 - 1e-6);
+    (p / (1.0 - p)).ln()
+}
+
+/// What the last layer is trained towards.
+#[derive(Clone, Copy)]
+enum Target<'a> {
+    Cost(&'a Array1<f32>, &'a Cost),
+    /// Gradient of the loss with respect to the outputs.
+    Gradient(&'a Array1<f32>),
+    /// Gradient of the loss with respect to the last layer's inputs to its activation.
+    Logits(&'a Array1<f32>),
+}
+
+/// Measurements from one training step, taken before the weights are updated.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrainStats {
+    pub loss: f32,
+    /// L2 norm of the gradient over every weight and bias in the network.
+    pub grad_norm: f32,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Layer {
     weights: Array2<f32>,
@@ -93,10 +211,11 @@ impl Layer {
         Self { weights, biases, activation, child }
     }
 
-    pub fn random(inputs: usize, outputs: usize, activation: Activation) -> Self {
+    pub fn random<R: Rng>(inputs: usize, outputs: usize, activation: Activation, init: &Initializer, rng: &mut R) -> Self {
+        let (weights, biases) = init.layer(inputs, outputs, &activation, rng);
         Self {
-            weights: Array2::zeros((outputs, inputs)).map(|_: &f32| rand::random::<f32>() -0.5),
-            biases: Array1::zeros(outputs).map(|_: &f32| rand::random::<f32>() - 0.5),
+            weights,
+            biases,
             activation,
             child: None,
         }
@@ -109,11 +228,11 @@ impl Layer {
         }
     }
 
-    pub fn add_random_layer(&mut self, size: usize, activation: Activation) {
+    pub fn add_random_layer<R: Rng>(&mut self, size: usize, activation: Activation, init: &Initializer, rng: &mut R) {
         match &self.child {
-            Some(x) => x.borrow_mut().add_random_layer(size, activation),
+            Some(x) => x.borrow_mut().add_random_layer(size, activation, init, rng),
             None => self.add_layer( // TODO idk
-                Layer::random(self.biases.shape()[0], size, activation)
+                Layer::random(self.biases.shape()[0], size, activation, init, rng)
             ),
         }
     }
@@ -133,20 +252,66 @@ impl Layer {
     }
 
     pub fn train(&mut self, inputs: &Array1<f32>, outputs: &Array1<f32>, cost: &Cost, lr: f32) -> Array1<f32> {
-        let da = match &self.child {
+        self.backprop(inputs, Target::Cost(outputs, cost), lr, &mut TrainStats::default())
+    }
+
+    /// Like `train`, but also reports the loss and gradient norm of the step.
+    pub fn train_with_stats(&mut self, inputs: &Array1<f32>, outputs: &Array1<f32>, cost: &Cost, lr: f32) -> TrainStats {
+        self.train_with_input_grad(inputs, outputs, cost, lr).0
+    }
+
+    /// Like `train_with_stats`, but also returns the gradient of the loss with respect to `inputs`, for
+    /// passing on to whatever produced them.
+    pub fn train_with_input_grad(&mut self, inputs: &Array1<f32>, outputs: &Array1<f32>, cost: &Cost, lr: f32) -> (TrainStats, Array1<f32>) {
+        let mut stats = TrainStats::default();
+        let di = self.backprop(inputs, Target::Cost(outputs, cost), lr, &mut stats);
+        stats.grad_norm = stats.grad_norm.sqrt();
+        (stats, di)
+    }
+
+    /// Trains on the gradient of a loss computed further on with respect to this network's outputs, as when
+    /// its outputs feed other networks. Returns the gradient norm of the step.
+    pub fn train_on_gradient(&mut self, inputs: &Array1<f32>, doutput: &Array1<f32>, lr: f32) -> f32 {
+        let mut stats = TrainStats::default();
+        self.backprop(inputs, Target::Gradient(doutput), lr, &mut stats);
+        stats.grad_norm.sqrt()
+    }
+
+    /// Like `train_on_gradient`, but the gradient is with respect to the last layer's logits, skipping its
+    /// activation. Returns the gradient norm of the step and the gradient with respect to `inputs`.
+    pub fn train_on_logits(&mut self, inputs: &Array1<f32>, dlogits: &Array1<f32>, lr: f32) -> (f32, Array1<f32>) {
+        let mut stats = TrainStats::default();
+        let di = self.backprop(inputs, Target::Logits(dlogits), lr, &mut stats);
+        (stats.grad_norm.sqrt(), di)
+    }
+
+    /// Accumulates the loss and the squared gradient norm into `stats`.
+    fn backprop(&mut self, inputs: &Array1<f32>, target: Target, lr: f32, stats: &mut TrainStats) -> Array1<f32> {
+        let (da, logits) = match &self.child {
             Some(x) => {
                 let act = self.apply(inputs);
-                x.borrow_mut().train(&act, outputs, cost, lr)
+                (x.borrow_mut().backprop(&act, target, lr, stats), false)
+            },
+            None => match target {
+                Target::Cost(outputs, cost) => {
+                    let prediction = self.apply(inputs);
+                    stats.loss = cost.apply(&prediction, outputs).sum();
+                    (cost.diff(&prediction, outputs), false)
+                },
+                Target::Gradient(doutput) => (doutput.clone(), false),
+                Target::Logits(dlogits) => (dlogits.clone(), true),
             },
-            None => cost.diff(&self.apply(inputs), outputs)
         };
-        
-        let (dw, db, _) = self.differentiate(inputs, &da);
+        // The input gradient has to come from the weights that produced the outputs, before they are updated.
+        let (dw, db, di) = match logits {
+            true => self.differentiate_logits(inputs, &da),
+            false => self.differentiate(inputs, &da),
+        };
+        stats.grad_norm += dw.iter().chain(&db).map(|x| x * x).sum::<f32>();
 
         self.weights -= &(&dw * lr);
         self.biases -= &(&db * lr);
 
-        let (_, _, di) = self.differentiate(inputs, &da);
         di
     }
 
@@ -162,6 +327,28 @@ impl Layer {
         (dw, db, da)
     }
 
+    fn differentiate_logits(&self, inputs: &Array1<f32>, dlogits: &Array1<f32>) -> (Array2<f32>, Array1<f32>, Array1<f32>) {
+        let dw = &inputs.slice(s![NewAxis, ..]) * &dlogits.slice(s![.., NewAxis]);
+        (dw, dlogits.clone(), dlogits.dot(&self.weights))
+    }
+
+    /// Inputs to the last layer's activation.
+    pub fn logits(&self, inputs: &Array1<f32>) -> Array1<f32> {
+        match &self.child {
+            Some(x) => x.borrow().logits(&self.apply(inputs)),
+            None => self.weights.dot(inputs) + &self.biases,
+        }
+    }
+
+    /// Frobenius norm of each layer's weights, from the input layer down.
+    pub fn weight_norms(&self) -> Vec<f32> {
+        let mut norms = vec![self.weights.iter().map(|x| x * x).sum::<f32>().sqrt()];
+        if let Some(child) = &self.child {
+            norms.extend(child.borrow().weight_norms());
+        }
+        norms
+    }
+
     pub fn scale(&mut self, sf: f32) {
         self.weights *= sf;
         self.biases *= sf;
@@ -173,10 +360,12 @@ impl Layer {
 
 
 pub fn test() {
-    let mut network = Layer::random(512, 64, Activation::LeakyReLU);
-    network.add_random_layer(64, Activation::LeakyReLU);
-    network.add_random_layer(8, Activation::LeakyReLU);
-    network.add_random_layer(2, Activation::LeakyReLU);
+    let rng = &mut rand::thread_rng();
+    let init = Initializer::default();
+    let mut network = Layer::random(512, 64, Activation::LeakyReLU, &init, rng);
+    network.add_random_layer(64, Activation::LeakyReLU, &init, rng);
+    network.add_random_layer(8, Activation::LeakyReLU, &init, rng);
+    network.add_random_layer(2, Activation::LeakyReLU, &init, rng);
 
     let res = network.predict(&arr1(&vec![1.0; 512]));
     println!("{}", res);
@@ -185,4 +374,48 @@ pub fn test() {
     }
     let res = network.predict(&arr1(&vec![1.0; 512]));
     println!("{}", res);
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn std_dev(x: &Array2<f32>) -> f32 {
+        let mean = x.mean().unwrap();
+        (x.map(|x| (x - mean).powi(2)).mean().unwrap()).sqrt()
+    }
+
+    #[test]
+    fn schemes_scale_with_fan_in() {
+        let rng = &mut StdRng::seed_from_u64(0);
+        let init = Initializer { scheme: InitScheme::He, zero_biases: true };
+        let layer = Layer::random(800, 200, Activation::ReLU, &init, rng);
+
+        assert!((std_dev(&layer.weights) - (2.0 / 800.0f32).sqrt()).abs() < 0.002);
+        assert!(layer.biases.iter().all(|x| *x == 0.0));
+
+        let init = Initializer { scheme: InitScheme::Xavier, zero_biases: false };
+        let layer = Layer::random(800, 200, Activation::Softmax, &init, rng);
+        assert!((std_dev(&layer.weights) - (2.0 / 1000.0f32).sqrt()).abs() < 0.002);
+        assert!(layer.biases.iter().any(|x| *x != 0.0));
+    }
+
+    #[test]
+    fn auto_picks_from_activation() {
+        assert_eq!(InitScheme::for_activation(&Activation::LeakyReLU), InitScheme::He);
+        assert_eq!(InitScheme::for_activation(&Activation::Softmax), InitScheme::Xavier);
+        assert_eq!("glorot".parse::<InitScheme>().unwrap(), InitScheme::Xavier);
+        assert!("normal".parse::<InitScheme>().is_err());
+    }
+
+    #[test]
+    fn input_gradient_uses_weights_before_the_step() {
+        let mut layer = Layer::new(array![[1.0, 2.0], [3.0, 4.0]], array![0.0, 0.0], Activation::Linear, None);
+        let di = layer.train(&array![1.0, 1.0], &array![0.0, 0.0], &Cost::Mse, 0.1);
+
+        // Outputs [3, 7] give d loss / d outputs = [3, 7], and W^T [3, 7] = [24, 34].
+        assert_eq!(di, array![24.0, 34.0]);
+        assert_ne!(layer.weights, array![[1.0, 2.0], [3.0, 4.0]]);
+    }
+}
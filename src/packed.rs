@@ -0,0 +1,383 @@
+use std::{fs::File, io::{BufReader, BufWriter, ErrorKind, Read, Write}};
+
+use anyhow::{anyhow, bail, Result};
+use cozy_chess::BoardBuilder;
+use cozy_chess_types::{CastleRights, Color, File as BoardFile, Piece, Rank, Square};
+use rusqlite::{params, Connection};
+
+use crate::{
+    chess::ChessState,
+    database::{add_counts, decode_board, upsert_position, Split, TargetSmoothing, Instance},
+    engine::{selfplay::GameOutcome, tools::AccumulativeAnalysis},
+};
+
+/// Size in bytes of one record. All multi-byte fields are little-endian:
+///
+/// | bytes | field |
+/// |-------|-------|
+/// | 0..32 | board, 4 bits per square from a1 to h8, low nibble first: 0 empty, 1-6 White P N B R Q K, 9-14 Black |
+/// | 32    | bit 0 side to move (1 = Black), bits 1-4 castling rights K Q k q |
+/// | 33    | en passant file + 1, 0 when there is none |
+/// | 34    | halfmove clock |
+/// | 35    | result: 0 unknown, 1 White win, 2 draw, 3 Black win |
+/// | 36..38| ply, u16 |
+/// | 38..40| score, White's win probability scaled to u16 |
+/// | 40..52| games won by White, drawn and won by Black, u32 each, all 0 when unknown |
+///
+/// Castling rights assume standard chess, with rooks starting on the a and h files.
+pub const RECORD_SIZE: usize = 52;
+
+const PIECES: [Piece; 6] = [Piece::Pawn, Piece::Knight, Piece::Bishop, Piece::Rook, Piece::Queen, Piece::King];
+
+/// How many games reaching a position White won, drew and lost.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GameCounts {
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct PackedRecord {
+    pub state: ChessState,
+    /// White's win probability.
+    pub score: f32,
+    pub result: Option<GameOutcome>,
+    /// Set for positions exported from the database, which aggregate many games.
+    pub counts: Option<GameCounts>,
+}
+
+impl PackedRecord {
+    pub fn encode(&self) -> [u8; RECORD_SIZE] {
+        let board = &self.state.board;
+        let mut buf = [0; RECORD_SIZE];
+
+        for sq in Square::ALL {
+            let Some(piece) = board.piece_on(sq) else { continue };
+            let mut nibble = PIECES.iter().position(|x| *x == piece).unwrap() as u8 + 1;
+            if board.color_on(sq) == Some(Color::Black) { nibble |= 8 }
+            buf[sq as usize / 2] |= nibble << (4 * (sq as usize % 2));
+        }
+
+        let mut flags = (board.side_to_move() == Color::Black) as u8;
+        for (i, color) in [Color::White, Color::Black].into_iter().enumerate() {
+            let rights = board.castle_rights(color);
+            if rights.short.is_some() { flags |= 1 << (1 + 2 * i) }
+            if rights.long.is_some() { flags |= 1 << (2 + 2 * i) }
+        }
+        buf[32] = flags;
+        buf[33] = board.en_passant().map_or(0, |x| x as u8 + 1);
+        buf[34] = board.halfmove_clock();
+        buf[35] = match self.result {
+            None => 0,
+            Some(GameOutcome::WhiteWin) => 1,
+            Some(GameOutcome::Draw) => 2,
+            Some(GameOutcome::BlackWin) => 3,
+        };
+        buf[36..38].copy_from_slice(&(self.state.ply() as u16).to_le_bytes());
+        buf[38..40].copy_from_slice(&((self.score.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16).to_le_bytes());
+        if let Some(counts) = self.counts {
+            for (i, x) in [counts.wins, counts.draws, counts.losses].into_iter().enumerate() {
+                buf[40 + 4 * i..44 + 4 * i].copy_from_slice(&x.to_le_bytes());
+            }
+        }
+
+        buf
+    }
+
+    pub fn decode(buf: &[u8; RECORD_SIZE]) -> Result<Self> {
+        let mut builder = BoardBuilder::empty();
+
+        for sq in Square::ALL {
+            let nibble = buf[sq as usize / 2] >> (4 * (sq as usize % 2)) & 0xF;
+            if nibble == 0 { continue }
+
+            let piece = *PIECES.get((nibble & 7) as usize - 1).ok_or_else(|| anyhow!("Invalid piece {nibble} on {sq}"))?;
+            let color = if nibble & 8 == 0 { Color::White } else { Color::Black };
+            *builder.square_mut(sq) = Some((piece, color));
+        }
+
+        let flags = buf[32];
+        builder.side_to_move = if flags & 1 == 0 { Color::White } else { Color::Black };
+        for (i, color) in [Color::White, Color::Black].into_iter().enumerate() {
+            *builder.castle_rights_mut(color) = CastleRights {
+                short: (flags & 1 << (1 + 2 * i) != 0).then_some(BoardFile::H),
+                long: (flags & 1 << (2 + 2 * i) != 0).then_some(BoardFile::A),
+            };
+        }
+
+        builder.en_passant = match buf[33] {
+            0 => None,
+            x @ 1..=8 => {
+                let rank = match builder.side_to_move {
+                    Color::White => Rank::Sixth,
+                    Color::Black => Rank::Third,
+                };
+                Some(Square::new(BoardFile::index(x as usize - 1), rank))
+            },
+            x => bail!("Invalid en passant file {x}"),
+        };
+        builder.halfmove_clock = buf[34];
+
+        let ply = u16::from_le_bytes([buf[36], buf[37]]);
+        builder.fullmove_number = ply / 2 + 1;
+
+        let result = match buf[35] {
+            0 => None,
+            1 => Some(GameOutcome::WhiteWin),
+            2 => Some(GameOutcome::Draw),
+            3 => Some(GameOutcome::BlackWin),
+            x => bail!("Invalid result {x}"),
+        };
+        let score = u16::from_le_bytes([buf[38], buf[39]]) as f32 / u16::MAX as f32;
+
+        let count = |i: usize| u32::from_le_bytes(buf[40 + 4 * i..44 + 4 * i].try_into().unwrap());
+        let counts = GameCounts { wins: count(0), draws: count(1), losses: count(2) };
+        let counts = (counts != GameCounts::default()).then_some(counts);
+
+        let board = builder.build().map_err(|e| anyhow!("Invalid position: {e:?}"))?;
+        Ok(Self { state: ChessState { board }, score, result, counts })
+    }
+}
+
+pub struct PackedWriter<W: Write> {
+    writer: W,
+}
+
+impl PackedWriter<BufWriter<File>> {
+    pub fn create(path: &str) -> Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> PackedWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn write(&mut self, record: &PackedRecord) -> Result<()> {
+        Ok(self.writer.write_all(&record.encode())?)
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Streams records one at a time, so files don't need to fit in memory. A file that ends partway through a
+/// record yields an error for it rather than ending quietly.
+pub struct PackedReader<R: Read> {
+    reader: R,
+}
+
+impl PackedReader<BufReader<File>> {
+    pub fn open(path: &str) -> Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: Read> PackedReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+}
+
+impl<R: Read> Iterator for PackedReader<R> {
+    type Item = Result<PackedRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = [0; RECORD_SIZE];
+        let mut read = 0;
+        while read < RECORD_SIZE {
+            match self.reader.read(&mut buf[read..]) {
+                Ok(0) if read == 0 => return None,
+                Ok(0) => return Some(Err(anyhow!("Truncated record: {read} of {RECORD_SIZE} bytes"))),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+        Some(PackedRecord::decode(&buf))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PositionExport {
+    pub written: usize,
+    /// Hashes of rows that were skipped because they couldn't be read or decoded, as in `Batch::failed`.
+    pub failed: Vec<i64>,
+}
+
+impl PositionExport {
+    pub fn skipped(&self) -> usize {
+        self.failed.len()
+    }
+}
+
+/// Writes the `chess_moves` rows in `split` seen more than `min_occurences` times, scored with `smoothing`.
+/// Rows only hold aggregate counts, so their result is left unknown and the counts are kept instead.
+pub fn export_positions<W: Write>(
+    conn: &Connection,
+    split: Split,
+    min_occurences: usize,
+    smoothing: &TargetSmoothing,
+    writer: &mut PackedWriter<W>,
+) -> Result<PositionExport> {
+    let mut stmnt = conn.prepare("SELECT hash, board, wins, draws, losses FROM chess_moves WHERE wins + draws + losses > ?1 AND split = ?2")?;
+    let mut rows = stmnt.query(params![min_occurences, split.id()])?;
+
+    let mut stats = PositionExport::default();
+    while let Some(row) = rows.next()? {
+        let hash: i64 = row.get(0)?;
+        let read = || -> Result<(ChessState, u64, u64, u64)> {
+            Ok((decode_board(&row.get::<_, String>(1)?)?, row.get(2)?, row.get(3)?, row.get(4)?))
+        };
+        let Ok((board, wins, draws, losses)) = read() else {
+            stats.failed.push(hash);
+            continue;
+        };
+
+        let instance = Instance::new(board, wins, draws, losses);
+        let clamp = |x: u64| x.min(u32::MAX as u64) as u32;
+        writer.write(&PackedRecord {
+            score: instance.target(smoothing).0,
+            state: instance.board,
+            result: None,
+            counts: Some(GameCounts { wins: clamp(wins), draws: clamp(draws), losses: clamp(losses) }),
+        })?;
+        stats.written += 1;
+    }
+
+    Ok(stats)
+}
+
+/// Writes the positions of a search tree visited more than `threshold` times, scored by their search value.
+pub fn export_analysis<W: Write>(analysis: &AccumulativeAnalysis, threshold: usize, writer: &mut PackedWriter<W>) -> Result<usize> {
+    let mut written = 0;
+    for (score, state) in analysis.training_positions(threshold) {
+        writer.write(&PackedRecord { state, score, result: None, counts: None })?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RecordImport {
+    pub imported: usize,
+    /// Records with neither game counts nor a result, which can't be turned into win/loss counts.
+    pub skipped: usize,
+}
+
+/// Adds every record with game counts or a known result to `chess_moves`, in a single transaction.
+pub fn import_records(conn: &mut Connection, path: &str) -> Result<RecordImport> {
+    import_from(conn, PackedReader::open(path)?)
+}
+
+fn import_from<R: Read>(conn: &mut Connection, records: PackedReader<R>) -> Result<RecordImport> {
+    let tx = conn.transaction()?;
+    let mut stats = RecordImport::default();
+
+    for record in records {
+        let record = record?;
+        match (record.counts, record.result) {
+            (Some(x), _) => add_counts(&tx, &record.state, x.wins as u64, x.draws as u64, x.losses as u64)?,
+            (None, Some(outcome)) => upsert_position(&tx, &record.state, outcome)?,
+            (None, None) => {
+                stats.skipped += 1;
+                continue;
+            },
+        }
+        stats.imported += 1;
+    }
+
+    tx.commit()?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::migrate;
+
+    #[test]
+    fn records_round_trip() {
+        let fens = [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "r3k2r/pp1n1ppp/8/2pP4/8/8/PPP2PPP/R3K2R w Kq c6 0 12",
+            "4k3/8/8/8/3pP3/8/8/4K2R b K e3 37 80",
+        ];
+
+        let mut writer = PackedWriter::new(vec![]);
+        for (i, fen) in fens.iter().enumerate() {
+            let record = PackedRecord {
+                state: ChessState::from_fen(fen).unwrap(),
+                score: i as f32 / 2.0,
+                result: Some(GameOutcome::Draw),
+                counts: (i > 0).then_some(GameCounts { wins: i as u32, draws: 70_000, losses: u32::MAX }),
+            };
+            writer.write(&record).unwrap();
+        }
+        let buf = writer.finish().unwrap();
+        assert_eq!(buf.len(), fens.len() * RECORD_SIZE);
+
+        let records = PackedReader::new(buf.as_slice()).collect::<Result<Vec<_>>>().unwrap();
+        for (i, (record, fen)) in records.iter().zip(fens).enumerate() {
+            assert_eq!(record.state.fen(), fen);
+            assert!((record.score - i as f32 / 2.0).abs() < 1e-4);
+            assert!(matches!(record.result, Some(GameOutcome::Draw)));
+            assert_eq!(record.counts.is_some(), i > 0);
+        }
+        assert_eq!(records[2].counts, Some(GameCounts { wins: 2, draws: 70_000, losses: u32::MAX }));
+    }
+
+    #[test]
+    fn truncated_records_are_an_error() {
+        let mut writer = PackedWriter::new(vec![]);
+        let record = PackedRecord { state: ChessState::default(), score: 0.5, result: None, counts: None };
+        writer.write(&record).unwrap();
+        writer.write(&record).unwrap();
+        let mut buf = writer.finish().unwrap();
+        buf.truncate(RECORD_SIZE + 5);
+
+        let mut reader = PackedReader::new(buf.as_slice());
+        assert!(reader.next().unwrap().is_ok());
+        let error = reader.next().unwrap().unwrap_err().to_string();
+        assert_eq!(error, format!("Truncated record: 5 of {RECORD_SIZE} bytes"));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn database_round_trips_through_records() {
+        let source = Connection::open_in_memory().unwrap();
+        migrate(&source).unwrap();
+        let mut state = ChessState::default();
+        for (i, mv) in ["e2e4", "e7e5", "g1f3"].iter().enumerate() {
+            add_counts(&source, &state, i as u64 + 1, 2, 3).unwrap();
+            state.board.play_unchecked(mv.parse().unwrap());
+        }
+        source.execute("UPDATE chess_moves SET split = 0", []).unwrap();
+        // An undecodable row is skipped and reported rather than ending the export.
+        let hash = ChessState::default().board.hash() as i64 + 1;
+        source.execute("INSERT INTO chess_moves (hash, board, wins, split) VALUES (?1, 'garbage', 1, 0)", params![hash]).unwrap();
+
+        let mut writer = PackedWriter::new(vec![]);
+        let export = export_positions(&source, Split::Train, 0, &TargetSmoothing::default(), &mut writer).unwrap();
+        assert_eq!((export.written, export.failed), (3, vec![hash]));
+        source.execute("DELETE FROM chess_moves WHERE hash = ?1", params![hash]).unwrap();
+
+        let mut target = Connection::open_in_memory().unwrap();
+        migrate(&target).unwrap();
+        let buf = writer.finish().unwrap();
+        let stats = import_from(&mut target, PackedReader::new(buf.as_slice())).unwrap();
+        assert_eq!((stats.imported, stats.skipped), (3, 0));
+
+        let rows = |conn: &Connection| conn
+            .prepare("SELECT hash, board, wins, draws, losses FROM chess_moves ORDER BY hash").unwrap()
+            .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, u64>(2)?, row.get::<_, u64>(3)?, row.get::<_, u64>(4)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows(&source), rows(&target));
+    }
+}
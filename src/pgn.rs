@@ -0,0 +1,459 @@
+use std::{collections::{HashSet, VecDeque}, fs::File, io::{BufRead, BufReader}, time::Instant};
+
+use anyhow::{anyhow, bail, Result};
+use cozy_chess::Board;
+use cozy_chess_types::{Move, Piece, Square};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{chess::ChessState, database::upsert_position, engine::selfplay::GameOutcome};
+
+/// Games imported per transaction; progress is saved at the same points so an interrupted import resumes from there.
+const IMPORT_CHUNK: usize = 500;
+
+#[derive(Debug, Clone, Default)]
+pub struct PgnGame {
+    pub tags: Vec<(String, String)>,
+    /// Mainline moves in SAN, with comments, variations, NAGs and move numbers removed.
+    pub moves: Vec<String>,
+    pub result: Option<GameOutcome>,
+}
+
+impl PgnGame {
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn start(&self) -> Result<ChessState> {
+        match self.tag("FEN") {
+            Some(fen) => ChessState::from_fen(fen),
+            None => Ok(ChessState::default()),
+        }
+    }
+
+    /// Replays the mainline, returning every position from the start to the final one.
+    pub fn positions(&self) -> Result<Vec<ChessState>> {
+        let mut state = self.start()?;
+        let mut positions = vec![state.clone()];
+
+        for san in &self.moves {
+            let mv = parse_san(&state.board, san)?;
+            state.board.play_unchecked(mv);
+            positions.push(state.clone());
+        }
+
+        Ok(positions)
+    }
+}
+
+/// Streams games out of PGN text.
+pub struct PgnReader<R: BufRead> {
+    lines: std::io::Lines<R>,
+    game: PgnGame,
+    has_content: bool,
+    in_comment: bool,
+    variation_depth: usize,
+    ready: VecDeque<PgnGame>,
+}
+
+impl PgnReader<BufReader<File>> {
+    pub fn open(path: &str) -> Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> PgnReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+            game: PgnGame::default(),
+            has_content: false,
+            in_comment: false,
+            variation_depth: 0,
+            ready: VecDeque::new(),
+        }
+    }
+
+    fn finish_game(&mut self) {
+        if self.has_content {
+            self.ready.push_back(std::mem::take(&mut self.game));
+        }
+        self.game = PgnGame::default();
+        self.has_content = false;
+        self.variation_depth = 0;
+    }
+
+    fn read_line(&mut self, line: &str) {
+        if line.starts_with('%') { return }
+
+        let trimmed = line.trim();
+        if !self.in_comment && self.variation_depth == 0 && trimmed.starts_with('[') {
+            // A tag after movetext means the previous game had no result token.
+            if !self.game.moves.is_empty() { self.finish_game() }
+            if let Some(tag) = parse_tag(trimmed) {
+                self.game.tags.push(tag);
+                self.has_content = true;
+            }
+            return;
+        }
+
+        let mut token = String::new();
+        for c in line.chars() {
+            if self.in_comment {
+                if c == '}' { self.in_comment = false }
+                continue;
+            }
+
+            match c {
+                '{' | ';' | '(' | ')' => {
+                    self.read_token(&std::mem::take(&mut token));
+                    match c {
+                        '{' => self.in_comment = true,
+                        ';' => return,
+                        '(' => self.variation_depth += 1,
+                        _ => self.variation_depth = self.variation_depth.saturating_sub(1),
+                    }
+                },
+                c if c.is_whitespace() => self.read_token(&std::mem::take(&mut token)),
+                c => token.push(c),
+            }
+        }
+        self.read_token(&token);
+    }
+
+    fn read_token(&mut self, token: &str) {
+        if token.is_empty() || self.variation_depth > 0 { return }
+
+        let result = match token {
+            "1-0" => Some(Some(GameOutcome::WhiteWin)),
+            "0-1" => Some(Some(GameOutcome::BlackWin)),
+            "1/2-1/2" => Some(Some(GameOutcome::Draw)),
+            "*" => Some(None),
+            _ => None,
+        };
+        if let Some(result) = result {
+            self.game.result = result;
+            self.has_content = true;
+            self.finish_game();
+            return;
+        }
+
+        if token.starts_with('$') { return }
+
+        let mv = strip_move_number(token).trim_end_matches(['!', '?']);
+        if !mv.is_empty() {
+            self.game.moves.push(mv.to_owned());
+            self.has_content = true;
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for PgnReader<R> {
+    type Item = Result<PgnGame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.ready.is_empty() {
+            match self.lines.next() {
+                Some(Ok(line)) => self.read_line(&line),
+                Some(Err(e)) => return Some(Err(e.into())),
+                None => {
+                    self.finish_game();
+                    break;
+                },
+            }
+        }
+        self.ready.pop_front().map(Ok)
+    }
+}
+
+/// Removes a move number ("12." / "12...") that may be glued to the move after it. Digits only count as one
+/// when followed by a dot, so zero castling ("0-0") is left alone.
+fn strip_move_number(token: &str) -> &str {
+    let rest = token.trim_start_matches(|c: char| c.is_ascii_digit());
+    match rest.strip_prefix('.') {
+        Some(rest) if rest.len() < token.len() - 1 => rest.trim_start_matches('.'),
+        _ => token,
+    }
+}
+
+fn parse_tag(line: &str) -> Option<(String, String)> {
+    let inner = line.strip_prefix('[')?.trim_end().strip_suffix(']')?;
+    let (name, value) = inner.split_once(char::is_whitespace)?;
+    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    Some((name.to_owned(), value.replace("\\\"", "\"").replace("\\\\", "\\")))
+}
+
+fn parse_piece(c: char) -> Option<Piece> {
+    Some(match c {
+        'N' => Piece::Knight,
+        'B' => Piece::Bishop,
+        'R' => Piece::Rook,
+        'Q' => Piece::Queen,
+        'K' => Piece::King,
+        _ => return None,
+    })
+}
+
+/// Finds the legal move `san` describes in `board`.
+pub fn parse_san(board: &Board, san: &str) -> Result<Move> {
+    let san = san.trim_end_matches(['+', '#', '!', '?']);
+    let mut moves = vec![];
+    board.generate_moves(|mvs| {
+        moves.extend(mvs);
+        false
+    });
+
+    let castle = match san {
+        "O-O" | "0-0" => Some(true),
+        "O-O-O" | "0-0-0" => Some(false),
+        _ => None,
+    };
+    if let Some(short) = castle {
+        // cozy-chess encodes castling as the king capturing its own rook.
+        return moves.into_iter()
+            .find(|mv| {
+                board.piece_on(mv.from) == Some(Piece::King)
+                    && board.color_on(mv.to) == Some(board.side_to_move())
+                    && (mv.to.file() > mv.from.file()) == short
+            })
+            .ok_or_else(|| anyhow!("Illegal castling move `{san}`"));
+    }
+
+    let mut chars: Vec<char> = san.chars().collect();
+
+    let piece = match chars.first().copied().and_then(parse_piece) {
+        Some(p) => {
+            chars.remove(0);
+            p
+        },
+        None => Piece::Pawn,
+    };
+
+    let promotion = match chars.iter().rposition(|c| parse_piece(*c).is_some()) {
+        Some(i) if piece == Piece::Pawn => {
+            let p = parse_piece(chars[i]);
+            chars.truncate(i);
+            if chars.last() == Some(&'=') { chars.pop(); }
+            p
+        },
+        _ => None,
+    };
+
+    chars.retain(|c| *c != 'x' && *c != '-');
+    if chars.len() < 2 { bail!("Malformed move `{san}`") }
+
+    let to: Square = chars[chars.len() - 2..].iter().collect::<String>().parse()
+        .map_err(|_| anyhow!("Malformed move `{san}`"))?;
+    let hint = &chars[..chars.len() - 2];
+
+    let mut candidates = moves.into_iter().filter(|mv| {
+        mv.to == to
+            && mv.promotion == promotion
+            && board.piece_on(mv.from) == Some(piece)
+            && board.color_on(mv.to) != Some(board.side_to_move())
+            && hint.iter().all(|c| match c {
+                'a'..='h' => mv.from.to_string().starts_with(*c),
+                '1'..='8' => mv.from.to_string().ends_with(*c),
+                _ => false,
+            })
+    });
+
+    match (candidates.next(), candidates.next()) {
+        (Some(mv), None) => Ok(mv),
+        (Some(_), Some(_)) => bail!("Ambiguous move `{san}`"),
+        _ => bail!("Illegal move `{san}`"),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ImportProgress {
+    pub games: usize,
+    pub skipped: usize,
+    pub positions: usize,
+    pub errors: usize,
+    pub games_per_sec: f32,
+}
+
+/// Imports every finished game in the PGN file at `path` into `chess_moves`, counting each distinct position
+/// once per game under that game's result. Progress is stored per path, so re-running after an interruption
+/// continues after the last committed chunk.
+pub fn import_pgn(conn: &mut Connection, path: &str, mut progress: impl FnMut(&ImportProgress)) -> Result<ImportProgress> {
+    let done: usize = conn
+        .query_row("SELECT games FROM pgn_imports WHERE path = ?1", params![path], |row| row.get(0))
+        .optional()?
+        .unwrap_or(0);
+
+    let start = Instant::now();
+    let mut stats = ImportProgress { skipped: done, ..Default::default() };
+    let mut reader = PgnReader::open(path)?.skip(done).peekable();
+
+    while reader.peek().is_some() {
+        let tx = conn.transaction()?;
+
+        for game in reader.by_ref().take(IMPORT_CHUNK) {
+            stats.games += 1;
+
+            let game = game?;
+            let Some(outcome) = game.result else { continue };
+            let positions = match game.positions() {
+                Ok(x) => x,
+                Err(_) => {
+                    stats.errors += 1;
+                    continue;
+                },
+            };
+
+            let mut seen = HashSet::new();
+            for state in positions.iter().filter(|x| seen.insert(x.board.hash())) {
+                upsert_position(&tx, state, outcome)?;
+                stats.positions += 1;
+            }
+        }
+
+        tx.execute(
+            "INSERT INTO pgn_imports (path, games) VALUES (?1, ?2)
+            ON CONFLICT(path) DO UPDATE SET games = excluded.games",
+            params![path, done + stats.games],
+        )?;
+        tx.commit()?;
+
+        stats.games_per_sec = stats.games as f32 / start.elapsed().as_secs_f32().max(f32::EPSILON);
+        progress(&stats);
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn games(text: &str) -> Vec<PgnGame> {
+        PgnReader::new(text.as_bytes()).collect::<Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn zero_castling_survives_move_numbers() {
+        assert_eq!(strip_move_number("12.Nf3"), "Nf3");
+        assert_eq!(strip_move_number("12...0-0"), "0-0");
+        assert_eq!(strip_move_number("0-0-0"), "0-0-0");
+        assert_eq!(strip_move_number("..."), "...");
+
+        let game = &games("1. e4 e5 2. Nf3 Nc6 3. Bc4 Bc5 4. 0-0 Nf6 1-0")[0];
+        assert_eq!(game.moves[6], "0-0");
+        assert_eq!(game.positions().unwrap().len(), 9);
+    }
+
+    #[test]
+    fn reader_skips_comments_variations_and_nags() {
+        let text = r#"[Event "Test"]
+[White "A \"quoted\" name"]
+% escaped line 1. d4
+1. e4 {a comment
+spanning lines} e5 (1... c5 2. Nf3 (2. c3 d5) d6) 2. Nf3 $1 Nc6 ; 3. d4 is ignored
+3. Bb5!? a6?! 1/2-1/2
+"#;
+        let games = games(text);
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].tag("White"), Some("A \"quoted\" name"));
+        assert_eq!(games[0].moves, ["e4", "e5", "Nf3", "Nc6", "Bb5", "a6"]);
+        assert_eq!(games[0].result, Some(GameOutcome::Draw));
+    }
+
+    #[test]
+    fn reader_handles_glued_numbers_and_missing_results() {
+        let games = games("[Event \"One\"]\n\n1.e4 e5 2.Nf3\n\n[Event \"Two\"]\n\n1.d4 1...d5 2.c4 *\n3.Nc3 0-1\n");
+        assert_eq!(games.len(), 3);
+        assert_eq!(games[0].moves, ["e4", "e5", "Nf3"]);
+        assert_eq!(games[0].result, None);
+        assert_eq!(games[1].tag("Event"), Some("Two"));
+        assert_eq!(games[1].moves, ["d4", "d5", "c4"]);
+        assert_eq!(games[1].result, None);
+        assert_eq!(games[2].moves, ["Nc3"]);
+        assert_eq!(games[2].result, Some(GameOutcome::BlackWin));
+    }
+
+    fn san(fen: &str, san: &str) -> Result<Move> {
+        parse_san(&ChessState::from_fen(fen).unwrap().board, san)
+    }
+
+    fn mv(from: &str, to: &str, promotion: Option<Piece>) -> Move {
+        Move { from: from.parse().unwrap(), to: to.parse().unwrap(), promotion }
+    }
+
+    #[test]
+    fn san_disambiguation() {
+        let knights = "4k3/8/8/8/8/5N2/8/1N2K3 w - - 0 1";
+        assert_eq!(san(knights, "Nbd2").unwrap(), mv("b1", "d2", None));
+        assert_eq!(san(knights, "Nfxd2").unwrap(), mv("f3", "d2", None));
+        assert!(san(knights, "Nd2").unwrap_err().to_string().contains("Ambiguous"));
+
+        let rooks = "4k3/8/8/R7/8/8/8/R3K3 w - - 0 1";
+        assert_eq!(san(rooks, "R1a3").unwrap(), mv("a1", "a3", None));
+        assert_eq!(san(rooks, "R5a3+").unwrap(), mv("a5", "a3", None));
+        assert!(san(rooks, "Ra3").is_err());
+
+        let queens = "K7/8/k7/8/4Q2Q/8/8/7Q w - - 0 1";
+        assert_eq!(san(queens, "Qh4e1").unwrap(), mv("h4", "e1", None));
+        assert!(san(queens, "Qhe1").is_err());
+        assert!(san(queens, "Q4e1").is_err());
+    }
+
+    #[test]
+    fn san_promotions_and_castling() {
+        let pawn = "4k3/P7/8/8/8/8/8/4K3 w - - 0 1";
+        assert_eq!(san(pawn, "a8=Q+").unwrap(), mv("a7", "a8", Some(Piece::Queen)));
+        assert_eq!(san(pawn, "a8N").unwrap(), mv("a7", "a8", Some(Piece::Knight)));
+        assert!(san(pawn, "a8").is_err());
+
+        // cozy-chess castles by moving the king onto its rook.
+        let castles = "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1";
+        for (short, long) in [("O-O", "O-O-O"), ("0-0", "0-0-0")] {
+            assert_eq!(san(castles, short).unwrap(), mv("e1", "h1", None));
+            assert_eq!(san(castles, long).unwrap(), mv("e1", "a1", None));
+        }
+        assert!(san("r3k2r/8/8/8/8/8/8/R3K2R w - - 0 1", "O-O").is_err());
+    }
+
+    #[test]
+    fn san_rejects_illegal_moves() {
+        let start = ChessState::default().fen();
+        assert!(san(&start, "e5").unwrap_err().to_string().contains("Illegal"));
+        assert!(san(&start, "Qh5").is_err());
+        assert!(san(&start, "x").unwrap_err().to_string().contains("Malformed"));
+    }
+
+    #[test]
+    fn import_resumes_and_counts_draws() {
+        let path = std::env::temp_dir().join(format!("chester-import-{}.pgn", std::process::id()));
+        std::fs::write(&path, "1. e4 e5 1/2-1/2\n\n1. d4 d5 1-0\n\n1. e4 c5 0-1\n\n1. Ke2 *\n").unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let counts = |conn: &Connection, state: &ChessState| conn.query_row(
+            "SELECT wins, draws, losses FROM chess_moves WHERE hash = ?1",
+            params![state.board.hash() as i64],
+            |row| Ok((row.get::<_, u64>(0)?, row.get::<_, u64>(1)?, row.get::<_, u64>(2)?)),
+        ).unwrap();
+        let start = ChessState::default();
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::database::migrate(&conn).unwrap();
+        conn.execute("INSERT INTO pgn_imports (path, games) VALUES (?1, 1)", params![path]).unwrap();
+
+        let stats = import_pgn(&mut conn, &path, |_| ()).unwrap();
+        assert_eq!((stats.skipped, stats.games, stats.positions, stats.errors), (1, 3, 6, 0));
+        assert_eq!(counts(&conn, &start), (1, 0, 1));
+
+        let stats = import_pgn(&mut conn, &path, |_| ()).unwrap();
+        assert_eq!((stats.skipped, stats.games), (4, 0));
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::database::migrate(&conn).unwrap();
+        import_pgn(&mut conn, &path, |_| ()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut e4 = start.clone();
+        e4.board.play_unchecked(mv("e2", "e4", None));
+        assert_eq!(counts(&conn, &start), (1, 1, 1));
+        assert_eq!(counts(&conn, &e4), (0, 1, 1));
+    }
+}
@@ -0,0 +1,281 @@
+use std::{sync::mpsc::{self, Receiver, Sender}, thread};
+
+use anyhow::{anyhow, Result};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    arena::{Arena, ArenaConfig},
+    chess::ChessState,
+    engine::{ai::Thod, selfplay::{GameRecord, ResignationStats, SelfPlay, SelfPlayConfig}},
+    game::Game,
+    replay::{ReplayBuffer, ReplaySample, ReplayStore},
+};
+
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    pub workers: usize,
+    pub batch_size: usize,
+    /// Training batches the learner runs for every finished self-play game.
+    pub steps_per_game: f32,
+    /// Training batches between checkpoints being sent to the workers.
+    pub promote_every: usize,
+    /// Positions the replay buffer must hold before training starts.
+    pub min_positions: usize,
+    pub recency_half_life: f32,
+    pub policy_lr: f32,
+    pub value_lr: f32,
+    pub checkpoint: String,
+    pub max_games: Option<usize>,
+    pub selfplay: SelfPlayConfig,
+    /// When set, a checkpoint only replaces the current best network after winning an arena match against it.
+    pub gating: Option<ArenaConfig>,
+    pub gating_openings: Vec<ChessState>,
+    /// Seeds every worker and the learner. Set it together with `selfplay.nodes` for reproducible games; the
+    /// order in which workers' games reach the learner still depends on thread timing.
+    pub seed: Option<u64>,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            batch_size: 256,
+            steps_per_game: 1.0,
+            promote_every: 10,
+            min_positions: 1000,
+            recency_half_life: 100_000.0,
+            policy_lr: 0.08,
+            value_lr: 0.06,
+            checkpoint: "network.json".to_owned(),
+            max_games: None,
+            selfplay: SelfPlayConfig::default(),
+            gating: None,
+            gating_openings: vec![],
+            seed: None,
+        }
+    }
+}
+
+/// A network snapshot tagged with the generation it was promoted as.
+type Checkpoint = (u64, Thod);
+
+/// Runs self-play workers and a learner until `max_games` games have been played, returning the final network.
+pub fn run(thod: Thod, replay: ReplayBuffer, config: PipelineConfig) -> Result<Thod> {
+    let (games_tx, games_rx) = mpsc::channel();
+    let mut checkpoints = vec![];
+    let mut workers = vec![];
+    let mut rng = config.seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+
+    for _ in 0..config.workers.max(1) {
+        let (tx, rx) = mpsc::channel();
+        tx.send((replay.generation(), thod.clone())).map_err(|_| anyhow!("Self-play worker channel closed"))?;
+        checkpoints.push(tx);
+
+        let games = games_tx.clone();
+        let selfplay = config.selfplay.clone();
+        let seed = rng.gen();
+        workers.push(thread::spawn(move || actor(rx, games, selfplay, StdRng::seed_from_u64(seed))));
+    }
+    drop(games_tx);
+
+    let rng = StdRng::seed_from_u64(rng.gen());
+    let learner = thread::spawn(move || learner(thod, replay, games_rx, checkpoints, config, rng));
+    let thod = learner.join().map_err(|_| anyhow!("Learner thread panicked"))??;
+
+    for worker in workers {
+        worker.join().map_err(|_| anyhow!("Self-play worker panicked"))?;
+    }
+
+    Ok(thod)
+}
+
+fn actor(checkpoints: Receiver<Checkpoint>, games: Sender<(u64, GameRecord)>, config: SelfPlayConfig, mut rng: StdRng) {
+    let Ok((mut generation, mut thod)) = checkpoints.recv() else { return };
+
+    loop {
+        // Only the newest checkpoint matters.
+        while let Ok((g, t)) = checkpoints.try_recv() {
+            generation = g;
+            thod = t;
+        }
+
+        let game = SelfPlay::new(&thod, config.clone()).play(&mut rng);
+        if games.send((generation, game)).is_err() { break }
+    }
+}
+
+fn learner(
+    mut thod: Thod,
+    mut replay: ReplayBuffer,
+    games: Receiver<(u64, GameRecord)>,
+    checkpoints: Vec<Sender<Checkpoint>>,
+    config: PipelineConfig,
+    mut rng: StdRng,
+) -> Result<Thod> {
+    let mut generation = replay.generation();
+    let mut best = thod.clone();
+    let arena = config.gating.clone().map(|x| Arena::new(x).with_openings(config.gating_openings.clone()));
+    let mut played = 0;
+    let mut steps = 0;
+    let mut resignations = ResignationStats::default();
+    let mut credit = 0.0;
+
+    for (g, game) in games.iter() {
+        played += 1;
+        println!("Game {played} (generation {g}): {} plies, {}", game.moves.len(), game.outcome);
+        if game.would_resign.is_some() {
+            resignations.add(&game);
+            println!("Resignation check: {resignations}");
+        }
+
+        replay.set_generation(g);
+        replay.push_game(&game)?;
+
+        if replay.len()? >= config.min_positions {
+            credit += config.steps_per_game;
+        }
+
+        while credit >= 1.0 {
+            credit -= 1.0;
+            steps += 1;
+
+            let batch = replay.sample_recent(config.batch_size, config.recency_half_life, &mut rng)?;
+            train(&mut thod, &batch, &config);
+
+            if steps % config.promote_every.max(1) == 0 {
+                if let Some(arena) = &arena {
+                    let gate = arena.gate(&thod, &best);
+                    println!("Gating after {steps} training steps: {}", gate.stats);
+                    if !gate.promoted { continue }
+                }
+
+                generation += 1;
+                best = thod.clone();
+                best.save(&config.checkpoint)?;
+                println!("Promoted generation {generation} after {steps} training steps");
+
+                for tx in &checkpoints {
+                    let _ = tx.send((generation, best.clone()));
+                }
+            }
+        }
+
+        if config.max_games.is_some_and(|x| played >= x) { break }
+    }
+
+    if arena.is_none() {
+        thod.save(&config.checkpoint)?;
+    }
+    Ok(thod)
+}
+
+/// Trains the policy on each position's search visit distribution and the value on the game's result.
+fn train(thod: &mut Thod, batch: &[ReplaySample], config: &PipelineConfig) {
+    for x in batch {
+        thod.train_moves(&x.sample.state, &x.sample.policy, config.policy_lr);
+        thod.train_value(&x.sample.state.state(), x.value, config.value_lr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, time::Duration};
+
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::{arena::SearchSettings, engine::adjudication::AdjudicationConfig, neural_net::Initializer};
+
+    fn tiny() -> Thod {
+        Thod::from_shape(vec![4], vec![4], Initializer::default(), &mut StdRng::seed_from_u64(0))
+    }
+
+    fn config(name: &str) -> PipelineConfig {
+        let adjudication = AdjudicationConfig { max_plies: Some(6), ..AdjudicationConfig::none() };
+        PipelineConfig {
+            workers: 1,
+            batch_size: 4,
+            min_positions: 0,
+            checkpoint: env::temp_dir().join(format!("chester-{name}-{}.json", std::process::id())).to_string_lossy().into_owned(),
+            selfplay: SelfPlayConfig { nodes: Some(8), adjudication, ..SelfPlayConfig::default() },
+            seed: Some(0),
+            ..PipelineConfig::default()
+        }
+    }
+
+    fn gating(threshold: f32) -> ArenaConfig {
+        ArenaConfig {
+            games: 2,
+            threads: 1,
+            threshold,
+            min_los: 2.0,
+            search: SearchSettings { move_time: Duration::from_millis(2), ..SearchSettings::default() },
+            adjudication: AdjudicationConfig { max_plies: Some(4), ..AdjudicationConfig::none() },
+        }
+    }
+
+    /// Runs the learner over `games` self-play games and returns the generations of the checkpoints it sent.
+    fn learn(games: usize, config: &PipelineConfig) -> Vec<u64> {
+        let thod = tiny();
+        let mut rng = StdRng::seed_from_u64(0);
+        let (games_tx, games_rx) = mpsc::channel();
+        for _ in 0..games {
+            games_tx.send((0, SelfPlay::new(&thod, config.selfplay.clone()).play(&mut rng))).unwrap();
+        }
+        drop(games_tx);
+
+        let (checkpoints_tx, checkpoints_rx) = mpsc::channel();
+        let replay = ReplayBuffer::new(Connection::open_in_memory().unwrap(), 1000).unwrap();
+        learner(thod, replay, games_rx, vec![checkpoints_tx], config.clone(), rng).unwrap();
+        checkpoints_rx.iter().map(|(g, _)| g).collect()
+    }
+
+    #[test]
+    fn learner_trains_in_the_configured_ratio() {
+        // Two games at half a step each make one step, which is promoted straight away.
+        let config = PipelineConfig { steps_per_game: 0.5, promote_every: 1, ..config("ratio") };
+        assert_eq!(learn(2, &config), [1]);
+        // Two steps per game, promoted every other step.
+        let config = PipelineConfig { steps_per_game: 2.0, promote_every: 2, ..config };
+        assert_eq!(learn(2, &config), [1, 2]);
+        assert!(fs::remove_file(&config.checkpoint).is_ok());
+    }
+
+    #[test]
+    fn gating_decides_whether_checkpoints_are_written() {
+        // Nothing can clear a threshold above a perfect score, so the first network stays the best.
+        let config = PipelineConfig { steps_per_game: 1.0, promote_every: 1, gating: Some(gating(2.0)), ..config("gated") };
+        assert!(learn(2, &config).is_empty());
+        assert!(fs::metadata(&config.checkpoint).is_err());
+
+        let config = PipelineConfig { gating: Some(gating(-1.0)), ..config };
+        assert_eq!(learn(2, &config), [1, 2]);
+        assert!(fs::remove_file(&config.checkpoint).is_ok());
+    }
+
+    #[test]
+    fn replay_generations_count_promotions() {
+        let path = env::temp_dir().join(format!("chester-generations-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let open = || ReplayBuffer::new(Connection::open(&path).unwrap(), 1000).unwrap();
+
+        // Four games, promoted every other game, so at most two promotions whatever the workers' timing.
+        let config = PipelineConfig { max_games: Some(4), steps_per_game: 0.5, promote_every: 1, ..config("generations") };
+        run(tiny(), open(), config.clone()).unwrap();
+
+        let samples = open().sample_uniform(1000, &mut StdRng::seed_from_u64(0)).unwrap();
+        assert!(!samples.is_empty());
+        assert!(samples.iter().all(|x| x.generation <= 2));
+        assert!(open().generation() <= 2);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&config.checkpoint).unwrap();
+    }
+
+    #[test]
+    fn run_stops_after_max_games() {
+        let config = PipelineConfig { max_games: Some(2), steps_per_game: 1.0, promote_every: 1, ..config("run") };
+        run(tiny(), ReplayBuffer::new(Connection::open_in_memory().unwrap(), 1000).unwrap(), config.clone()).unwrap();
+        assert!(fs::remove_file(&config.checkpoint).is_ok());
+    }
+}
@@ -0,0 +1,267 @@
+use std::{fs::{File, OpenOptions}, io::{BufWriter, Write}};
+
+use anyhow::Result;
+use cozy_chess_types::Move;
+use rand::Rng;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::{chess::ChessState, database::migrate, engine::selfplay::{GameRecord, PolicySample}};
+
+pub trait ReplayStore {
+    fn push_game(&mut self, game: &GameRecord) -> Result<()>;
+}
+
+/// One self-play position as it is written to disk. Moves use cozy-chess notation so they parse back with `str::parse`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredPosition {
+    pub fen: String,
+    pub policy: Vec<(String, f32)>,
+    pub result: f32,
+}
+
+impl StoredPosition {
+    pub fn from_game(game: &GameRecord) -> Vec<Self> {
+        game.targets()
+            .map(|(sample, result)| Self {
+                fen: sample.state.fen(),
+                policy: sample.policy.iter().map(|(mv, p)| (mv.to_string(), *p)).collect(),
+                result,
+            })
+            .collect()
+    }
+}
+
+/// Appends every position as a line of JSON.
+pub struct JsonlStore {
+    file: BufWriter<File>,
+}
+
+impl JsonlStore {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: BufWriter::new(file) })
+    }
+}
+
+impl ReplayStore for JsonlStore {
+    fn push_game(&mut self, game: &GameRecord) -> Result<()> {
+        for position in StoredPosition::from_game(game) {
+            serde_json::to_writer(&mut self.file, &position)?;
+            self.file.write_all(b"\n")?;
+        }
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplaySample {
+    pub sample: PolicySample,
+    pub value: f32,
+    pub generation: u64,
+}
+
+/// Keeps the most recent `capacity` self-play positions in a `replay_positions` table.
+pub struct ReplayBuffer {
+    conn: Connection,
+    capacity: usize,
+    generation: u64,
+}
+
+impl ReplayBuffer {
+    pub fn new(conn: Connection, capacity: usize) -> Result<Self> {
+        migrate(&conn)?;
+
+        let generation = conn.query_row(
+            "SELECT COALESCE(MAX(generation), 0) FROM replay_positions", [], |row| row.get(0),
+        )?;
+
+        Ok(Self { conn, capacity, generation })
+    }
+
+    /// The current generation; a new buffer starts from the newest one stored, so a restarted pipeline keeps
+    /// counting promotions where it left off.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Tags positions pushed from now on as coming from network `generation`, the number of networks promoted
+    /// before the one that played them. Every game played by the same network shares it.
+    pub fn set_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.conn.query_row("SELECT COUNT(*) FROM replay_positions", [], |row| row.get(0))?)
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Draws `n` positions uniformly (with replacement) from the buffer.
+    pub fn sample_uniform<R: Rng>(&self, n: usize, rng: &mut R) -> Result<Vec<ReplaySample>> {
+        self.sample_by(n, rng, |len, rng| rng.gen_range(0..len))
+    }
+
+    /// Draws `n` positions with the chance of picking one halving every `half_life` positions back from the newest.
+    pub fn sample_recent<R: Rng>(&self, n: usize, half_life: f32, rng: &mut R) -> Result<Vec<ReplaySample>> {
+        let lambda = std::f32::consts::LN_2 / half_life.max(f32::EPSILON);
+        self.sample_by(n, rng, |len, rng| {
+            // Inverse CDF of an exponential truncated to [0, len).
+            let tail = 1.0 - (-lambda * len as f32).exp();
+            let age = -(1.0 - rng.gen::<f32>() * tail).ln() / lambda;
+            len - 1 - (age as usize).min(len - 1)
+        })
+    }
+
+    fn sample_by<R: Rng>(&self, n: usize, rng: &mut R, mut pick: impl FnMut(usize, &mut R) -> usize) -> Result<Vec<ReplaySample>> {
+        let (first, last): (Option<i64>, Option<i64>) = self.conn.query_row(
+            "SELECT MIN(id), MAX(id) FROM replay_positions", [], |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let (Some(first), Some(last)) = (first, last) else { return Ok(vec![]) };
+        let len = (last - first + 1) as usize;
+
+        let mut stmnt = self.conn.prepare_cached(
+            "SELECT generation, fen, policy, value FROM replay_positions WHERE id = ?1",
+        )?;
+
+        let mut samples = Vec::with_capacity(n);
+        for _ in 0..n {
+            let id = first + pick(len, rng) as i64;
+            let row = stmnt.query_row(params![id], |row| Ok((
+                row.get::<_, u64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, f32>(3)?,
+            ))).optional()?;
+
+            if let Some((generation, fen, policy, value)) = row {
+                let policy: Vec<(String, f32)> = serde_json::from_str(&policy)?;
+                samples.push(ReplaySample {
+                    sample: PolicySample {
+                        state: ChessState::from_fen(&fen)?,
+                        policy: policy.into_iter()
+                            .map(|(mv, p)| Ok((mv.parse::<Move>()?, p)))
+                            .collect::<Result<_>>()?,
+                    },
+                    value,
+                    generation,
+                });
+            }
+        }
+
+        Ok(samples)
+    }
+}
+
+impl ReplayStore for ReplayBuffer {
+    fn push_game(&mut self, game: &GameRecord) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmnt = tx.prepare_cached(
+                "INSERT INTO replay_positions (generation, fen, policy, value) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for position in StoredPosition::from_game(game) {
+                stmnt.execute(params![
+                    self.generation,
+                    position.fen,
+                    serde_json::to_string(&position.policy)?,
+                    position.result,
+                ])?;
+            }
+        }
+        tx.execute(
+            "DELETE FROM replay_positions WHERE id <= (SELECT MAX(id) FROM replay_positions) - ?1",
+            params![self.capacity],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::engine::selfplay::GameOutcome;
+
+    const GAME_LENGTH: usize = 10;
+
+    /// A buffer holding `games` games, each tagged with its index as generation.
+    fn buffer(games: u64, capacity: usize) -> ReplayBuffer {
+        let mut buffer = ReplayBuffer::new(Connection::open_in_memory().unwrap(), capacity).unwrap();
+        let sample = PolicySample { state: ChessState::default(), policy: vec![("e2e4".parse().unwrap(), 1.0)] };
+        let game = GameRecord {
+            start: ChessState::default(),
+            moves: vec![],
+            samples: vec![sample; GAME_LENGTH],
+            outcome: GameOutcome::WhiteWin,
+            would_resign: None,
+        };
+
+        for generation in 0..games {
+            buffer.set_generation(generation);
+            buffer.push_game(&game).unwrap();
+        }
+        buffer
+    }
+
+    fn mean_generation(samples: &[ReplaySample]) -> f32 {
+        samples.iter().map(|x| x.generation as f32).sum::<f32>() / samples.len() as f32
+    }
+
+    #[test]
+    fn samples_round_trip_and_stay_in_range() {
+        let buffer = buffer(10, 1000);
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(buffer.len().unwrap(), 10 * GAME_LENGTH);
+
+        let uniform = buffer.sample_uniform(500, &mut rng).unwrap();
+        let recent = buffer.sample_recent(500, 5.0, &mut rng).unwrap();
+        // Every pick lands on a stored row, so nothing is dropped.
+        assert_eq!((uniform.len(), recent.len()), (500, 500));
+
+        for x in uniform.iter().chain(&recent) {
+            assert!(x.generation < 10);
+            assert_eq!(x.value, 1.0);
+            assert_eq!(x.sample.policy[0].0.to_string(), "e2e4");
+        }
+    }
+
+    #[test]
+    fn recent_sampling_favours_new_games() {
+        let buffer = buffer(10, 1000);
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let uniform = buffer.sample_uniform(1000, &mut rng).unwrap();
+        let recent = buffer.sample_recent(1000, GAME_LENGTH as f32, &mut rng).unwrap();
+        assert!((mean_generation(&uniform) - 4.5).abs() < 0.5);
+        // With a half-life of one game, about half the picks come from the newest game.
+        assert!(mean_generation(&recent) > 8.0);
+        let newest = recent.iter().filter(|x| x.generation == 9).count();
+        assert!((400..600).contains(&newest), "{newest}");
+    }
+
+    #[test]
+    fn capacity_trims_the_oldest_positions() {
+        let buffer = buffer(5, 25);
+        let mut rng = StdRng::seed_from_u64(2);
+        assert_eq!(buffer.len().unwrap(), 25);
+
+        let samples = buffer.sample_uniform(500, &mut rng).unwrap();
+        assert_eq!(samples.len(), 500);
+        // Half of the third game survives and nothing older.
+        assert!(samples.iter().all(|x| x.generation >= 2));
+        assert!(samples.iter().any(|x| x.generation == 2));
+    }
+
+    #[test]
+    fn empty_buffer_samples_nothing() {
+        let buffer = buffer(0, 10);
+        assert!(buffer.is_empty().unwrap());
+        assert!(buffer.sample_recent(10, 5.0, &mut StdRng::seed_from_u64(3)).unwrap().is_empty());
+    }
+}
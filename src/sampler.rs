@@ -0,0 +1,260 @@
+use std::{collections::HashMap, sync::mpsc::{sync_channel, Receiver}, thread};
+
+use cozy_chess::Move;
+use ndarray::Array1;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use rusqlite::{params, params_from_iter, Connection};
+
+use crate::database::{move_distribution, Batch, DatabaseError, Instance, Split};
+
+/// Ids bound per query, to stay under the 999 variable limit of SQLite builds before 3.32.
+const MAX_VARIABLES: usize = 900;
+
+/// Walks `chess_moves` in epochs: the matching row ids are read once, then every epoch visits each of them
+/// exactly once in a freshly shuffled order.
+pub struct EpochSampler {
+    ids: Vec<i64>,
+    position: usize,
+    epoch: usize,
+    rng: StdRng,
+}
+
+impl EpochSampler {
+    /// Samples positions from `split` seen more than `min_occurences` times.
+    pub fn new(conn: &Connection, split: Split, min_occurences: usize, seed: u64) -> Result<Self, DatabaseError> {
+        let ids = conn
+            .prepare("SELECT hash FROM chess_moves WHERE wins + draws + losses > ?1 AND split = ?2 ORDER BY hash")?
+            .query_map(params![min_occurences, split.id()], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+        let mut sampler = Self { ids, position: 0, epoch: 0, rng: StdRng::seed_from_u64(seed) };
+        sampler.ids.shuffle(&mut sampler.rng);
+        Ok(sampler)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Number of completed passes over the data.
+    pub fn epoch(&self) -> usize {
+        self.epoch
+    }
+
+    /// Reads the next `size` rows. The last batch of an epoch may be shorter; the one after it starts a new,
+    /// reshuffled epoch.
+    pub fn next_batch(&mut self, conn: &Connection, size: usize) -> Result<Batch, DatabaseError> {
+        if self.position >= self.ids.len() {
+            self.ids.shuffle(&mut self.rng);
+            self.position = 0;
+            self.epoch += 1;
+        }
+
+        let end = (self.position + size).min(self.ids.len());
+        let ids = &self.ids[self.position..end];
+        self.position = end;
+
+        let mut batch = Batch::default();
+        if ids.is_empty() {
+            return Ok(batch);
+        }
+
+        let mut rows = HashMap::with_capacity(ids.len());
+        for chunk in ids.chunks(MAX_VARIABLES) {
+            let placeholders = vec!["?"; chunk.len()].join(",");
+            let mut stmnt = conn.prepare_cached(&format!(
+                "SELECT hash, board, wins, draws, losses FROM chess_moves WHERE hash IN ({placeholders})"
+            ))?;
+            for row in stmnt.query_map(params_from_iter(chunk), |row| Ok((row.get::<_, i64>(0)?, (|| Ok((
+                row.get::<_, String>(1)?,
+                row.get::<_, u64>(2)?,
+                row.get::<_, u64>(3)?,
+                row.get::<_, u64>(4)?,
+            )))())))? {
+                let (id, row): (i64, rusqlite::Result<_>) = row?;
+                rows.insert(id, row);
+            }
+        }
+
+        // Keeps the shuffled order rather than the order the rows come back in.
+        for &id in ids {
+            match rows.remove(&id) {
+                Some(Ok((board, wins, draws, losses))) => match Instance::from_str(&board, wins, draws, losses) {
+                    Ok(x) => batch.instances.push(x),
+                    Err(_) => batch.failed.push(id),
+                },
+                // Deleted since the ids were read.
+                None => (),
+                Some(Err(_)) => batch.failed.push(id),
+            }
+        }
+
+        Ok(batch)
+    }
+}
+
+/// A batch with every board already encoded as network input.
+pub struct EncodedBatch {
+    pub batch: Batch,
+    pub states: Vec<Array1<f32>>,
+    /// Distribution of the moves played from each board, all empty unless requested.
+    pub moves: Vec<Vec<(Move, f32)>>,
+    /// Epoch the batch was drawn from.
+    pub epoch: usize,
+}
+
+/// Reads and encodes batches on a background thread, keeping up to `depth` of them ready ahead of the consumer.
+pub struct Prefetcher {
+    batches: Receiver<Result<EncodedBatch, DatabaseError>>,
+}
+
+impl Prefetcher {
+    /// Streams batches of `size` from `sampler` until `epochs` passes are done, or forever if it is `None`, and
+    /// stops after the first error. With `moves`, the move distribution of every board is read as well.
+    pub fn spawn(conn: Connection, mut sampler: EpochSampler, size: usize, depth: usize, epochs: Option<usize>, moves: bool) -> Self {
+        let (tx, rx) = sync_channel(depth);
+
+        thread::spawn(move || {
+            if sampler.is_empty() { return }
+
+            loop {
+                let batch = sampler.next_batch(&conn, size);
+                // The batch that starts the epoch after the last one is dropped before any work goes into it.
+                if epochs.is_some_and(|x| sampler.epoch() >= x) { break }
+
+                let batch = batch.and_then(|batch| Ok(EncodedBatch {
+                    states: batch.instances.iter().map(Instance::state).collect(),
+                    moves: batch.instances.iter()
+                        .map(|x| if moves { move_distribution(&conn, &x.board) } else { Ok(vec![]) })
+                        .collect::<Result<_, _>>()?,
+                    batch,
+                    epoch: sampler.epoch(),
+                }));
+                // An error ends the stream, as does the receiver being dropped.
+                let failed = batch.is_err();
+                if tx.send(batch).is_err() || failed { break }
+            }
+        });
+
+        Self { batches: rx }
+    }
+}
+
+impl Iterator for Prefetcher {
+    type Item = Result<EncodedBatch, DatabaseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.batches.recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chess::ChessState, database::{migrate, upsert_position}, engine::selfplay::GameOutcome};
+
+    /// Stores the positions along a game, returning how many distinct ones there were.
+    fn database(positions: usize) -> (Connection, usize) {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+
+        let mut state = ChessState::default();
+        for _ in 0..positions {
+            upsert_position(&conn, &state, GameOutcome::WhiteWin).unwrap();
+            let mut mv = None;
+            state.board.generate_moves(|mvs| {
+                mv = mvs.into_iter().next();
+                true
+            });
+            state.board.play_unchecked(mv.unwrap());
+        }
+
+        conn.execute("UPDATE chess_moves SET split = 0", []).unwrap();
+        let rows = conn.query_row("SELECT COUNT(*) FROM chess_moves", [], |row| row.get(0)).unwrap();
+        (conn, rows)
+    }
+
+    #[test]
+    fn epoch_visits_every_row_once() {
+        let (conn, rows) = database(10);
+        let mut sampler = EpochSampler::new(&conn, Split::Train, 0, 1).unwrap();
+
+        let mut seen = vec![];
+        while sampler.epoch() == 0 {
+            let batch = sampler.next_batch(&conn, 3).unwrap();
+            if sampler.epoch() > 0 { break }
+            seen.extend(batch.instances.iter().map(|x| x.board.board.hash()));
+        }
+
+        assert_eq!(seen.len(), rows);
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), rows);
+    }
+
+    #[test]
+    fn prefetcher_stops_after_epochs() {
+        let (conn, rows) = database(5);
+        let sampler = EpochSampler::new(&conn, Split::Train, 0, 1).unwrap();
+
+        let streamed: usize = Prefetcher::spawn(conn, sampler, 2, 2, Some(2), false)
+            .map(|x| x.unwrap().states.len())
+            .sum();
+        assert_eq!(streamed, 2 * rows);
+    }
+
+    #[test]
+    fn prefetcher_reads_moves_per_board() {
+        let (conn, _) = database(5);
+        let sampler = EpochSampler::new(&conn, Split::Train, 0, 1).unwrap();
+
+        for batch in Prefetcher::spawn(conn, sampler, 2, 2, Some(1), true) {
+            let batch = batch.unwrap();
+            assert_eq!(batch.moves.len(), batch.states.len());
+        }
+    }
+
+    #[test]
+    fn batches_keep_the_shuffled_order_and_skip_bad_rows() {
+        let (conn, rows) = database(8);
+        let mut sampler = EpochSampler::new(&conn, Split::Train, 0, 1).unwrap();
+        let ids = sampler.ids.clone();
+        conn.execute("UPDATE chess_moves SET board = 'garbage' WHERE hash = ?1", params![ids[2]]).unwrap();
+        conn.execute("DELETE FROM chess_moves WHERE hash = ?1", params![ids[5]]).unwrap();
+
+        let batch = sampler.next_batch(&conn, rows).unwrap();
+        let expected = ids.iter().enumerate().filter(|(i, _)| ![2, 5].contains(i)).map(|x| *x.1).collect::<Vec<_>>();
+        assert_eq!(batch.instances.iter().map(|x| x.board.board.hash() as i64).collect::<Vec<_>>(), expected);
+        assert_eq!(batch.failed, vec![ids[2]]);
+    }
+
+    #[test]
+    fn large_batches_are_read_in_chunks() {
+        let (conn, _) = database(0);
+        let board = ChessState::default().fen();
+        for hash in 0..2 * MAX_VARIABLES as i64 + 10 {
+            conn.execute("INSERT INTO chess_moves (hash, board, wins, split) VALUES (?1, ?2, 1, 0)", params![hash, board]).unwrap();
+        }
+        let mut sampler = EpochSampler::new(&conn, Split::Train, 0, 1).unwrap();
+        let ids = sampler.ids.clone();
+
+        let batch = sampler.next_batch(&conn, ids.len()).unwrap();
+        assert_eq!(batch.instances.len(), ids.len());
+        assert!(batch.failed.is_empty());
+    }
+
+    #[test]
+    fn prefetcher_stops_after_an_error() {
+        let (conn, _) = database(5);
+        let sampler = EpochSampler::new(&conn, Split::Train, 0, 1).unwrap();
+        conn.execute("DROP TABLE chess_moves", []).unwrap();
+
+        let batches = Prefetcher::spawn(conn, sampler, 2, 2, None, false).collect::<Vec<_>>();
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].is_err());
+    }
+}
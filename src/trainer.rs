@@ -1,66 +1,532 @@
-use chess::ChessState;
+use std::{env, path::Path, time::Instant};
+
+use anyhow::{anyhow, bail, Result};
+use chester::chess::ChessState;
 use chester::neural_net::Cost;
-use database::{init, load_to_memory, get_batch};
-use engine::tools::Tools;
-use ndarray::arr1;
-use crate::{database::Instance, engine::{tools::Analysis, ai::Thod}};
-use game::Game;
-
-mod engine;
-mod database;
-mod chess;
-mod game;
-mod neural_net;
-mod model;
-
-
-pub fn main() {
-    let broken = "AkBAQEBABkABAUADwwFAAUBAQAUBQAFAQEBAQEBAQEBAQEDBQEBAwUDBQMVAQEBAQMFAQEDBwUBAQEDCwkDGQA==";
-    Instance::from_str(broken, 0, 0);
-
-    let conn = init(&"chess.db".to_owned());
-    let conn = load_to_memory(&conn).unwrap();
-
-    let mut thod = Thod::from_file("test.json").unwrap();
-    // let mut thod = Thod::default();
-    let test = get_batch(&conn, 5, 64);
-
-    loop {
-        let batch = get_batch(&conn, 0, 10);
-
-        // if( e4){
-        //     surrender();
-
-        // } else {
-        //     win();
-        // }
-
-        for (idx, i) in batch.iter().enumerate() {
-            thod.train_policy(&i.state(), i.winrate().0, 0.02);
-            thod.train_value (&i.state(), i.winrate().0, 0.01);
-            
-            if idx % 1 == 0 {
-                println!("{idx}/200")
+use chester::database::{init, move_distribution, Batch, Instance, Split, TargetSmoothing};
+use chester::packed::{PackedReader, PackedRecord};
+use chester::sampler::{EpochSampler, Prefetcher};
+use chester::engine::ai::Thod;
+use chester::game::Game;
+use chester::metrics::{EpochMetrics, Evaluation, Metric, MetricsLog, MoveAccuracy, StepMetrics};
+use chester::training::{LrSchedule, Phase, PolicyTarget, TrainConfig};
+use ndarray::{arr1, Array1};
+use cozy_chess_types::Move;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rusqlite::Connection;
+
+const USAGE: &str = "\
+usage: trainer [options]
+
+Options after --config override the values it sets. The final config is saved next to --model-out.
+
+options:
+  --config FILE            load a saved run config
+  --database PATH          position database (default chess.db)
+  --records FILE           train from a packed record file instead of the database
+  --model-in FILE          network to start from (default: a new one)
+  --resume                 start from --model-out if it exists; recorded as --model-in in the saved config
+  --model-out FILE         where checkpoints are written (default test.json)
+  --metrics FILE           JSON-lines metrics log (default: next to --model-out)
+  --trunk-layers A,B,..    give a new network a shared trunk of these layer sizes, with the policy and
+                           value layers as its heads (default none, two separate networks)
+  --policy-layers A,B,..   hidden layer sizes of a new policy network or head, or none (default 500,250,100)
+  --value-layers A,B,..    hidden layer sizes of a new value network or head, or none
+                           (default 750,500,250,100,10)
+  --init SCHEME            weight initialisation of a new network: auto, he, xavier, lecun or uniform
+                           (default auto, which picks per layer from its activation)
+  --zero-biases            start the biases of a new network at zero
+  --phase MIN:EPOCHS       train EPOCHS epochs on positions seen more than MIN times; repeat for more
+                           phases (default 20:1 5:2 0:10)
+  --epochs N               epochs of the last phase
+  --batch-size N           positions per batch (default 10)
+  --policy-lr LR           policy learning rate (default 0.02)
+  --value-lr LR            value learning rate (default 0.01)
+  --policy-weight W        weight of the policy loss in the combined loss (default 1)
+  --value-weight W         weight of the value loss in the combined loss (default 1)
+  --policy-target TARGET   train the policy on the moves played from each position (moves, the default)
+                           or on the game result like the value network (result)
+  --lr-step N              multiply the learning rates by --lr-gamma every N batches (default 1000)
+  --lr-gamma G             learning rate decay factor (default 0.5)
+  --seed N                 seeds network initialisation and sampling (default 0)
+  --validate-every N       batches between validation reports and checkpoints (default 1)
+  --validation-size N      held-out positions in each report (default 64)
+  --validation-min N       only hold out positions seen more than N times (default 5)
+  --no-flip                don't train on colour-flipped positions";
+
+const PREFETCH_BATCHES: usize = 4;
+
+/// A position with White's win probability as target and its weight in the loss.
+struct Sample {
+    board: ChessState,
+    state: Array1<f32>,
+    target: f32,
+    weight: f32,
+    /// Distribution of the moves played from the position, empty when the policy trains on results.
+    moves: Vec<(Move, f32)>,
+}
+
+impl Sample {
+    fn from_instance(instance: &Instance, state: Array1<f32>, moves: Vec<(Move, f32)>, smoothing: &TargetSmoothing) -> Self {
+        Self {
+            board: instance.board.clone(),
+            state,
+            target: instance.target(smoothing).0,
+            weight: instance.weight(smoothing),
+            moves,
+        }
+    }
+
+    /// Records carry an already smoothed score and no counts, so they all weigh the same.
+    fn from_record(record: PackedRecord) -> Self {
+        Self { state: record.state.state(), board: record.state, target: record.score, weight: 1.0, moves: vec![] }
+    }
+
+    /// The sample with colours swapped, and its moves mirrored to match.
+    fn flipped(&self) -> Self {
+        let board = self.board.flipped();
+        Self {
+            state: board.state(),
+            board,
+            target: 1.0 - self.target,
+            weight: self.weight,
+            moves: self.moves.iter().map(|(mv, p)| (ChessState::flip_move(*mv), *p)).collect(),
+        }
+    }
+}
+
+fn report_skipped(batch: Batch) -> Batch {
+    if batch.skipped() > 0 {
+        eprintln!("Skipped {} invalid rows: {:?}", batch.skipped(), batch.failed);
+    }
+    batch
+}
+
+/// Running totals for the epoch in progress.
+struct EpochTotals {
+    epoch: usize,
+    batches: usize,
+    samples: usize,
+    /// Samples the policy was trained on, which with move targets leaves out those without moves.
+    policy_samples: usize,
+    policy_loss: f32,
+    value_loss: f32,
+    start: Instant,
+}
+
+impl EpochTotals {
+    fn new(epoch: usize) -> Self {
+        Self { epoch, batches: 0, samples: 0, policy_samples: 0, policy_loss: 0.0, value_loss: 0.0, start: Instant::now() }
+    }
+}
+
+struct Trainer {
+    thod: Thod,
+    config: TrainConfig,
+    validation: Vec<Sample>,
+    batches: usize,
+    log: MetricsLog,
+    epoch: Option<EpochTotals>,
+    rng: StdRng,
+}
+
+impl Trainer {
+    /// Records have no moves to train the policy on.
+    fn policy_target(&self) -> PolicyTarget {
+        match self.config.records {
+            Some(_) => PolicyTarget::Result,
+            None => self.config.policy_target,
+        }
+    }
+
+    fn moves(&self, conn: &Connection, board: &ChessState) -> Result<Vec<(Move, f32)>> {
+        Ok(match self.policy_target() {
+            PolicyTarget::Result => vec![],
+            PolicyTarget::Moves => move_distribution(conn, board)?,
+        })
+    }
+
+    fn step(&mut self, batch: &[Sample], epoch: usize) -> Result<()> {
+        if self.epoch.as_ref().is_some_and(|x| x.epoch != epoch) {
+            self.finish_epoch()?;
+        }
+        let (policy_target, weights) = (self.policy_target(), self.config.loss_weights);
+        let totals = self.epoch.get_or_insert_with(|| EpochTotals::new(epoch));
+
+        let start = Instant::now();
+        let lr = self.config.lr_schedule.factor(self.batches);
+        let (policy_lr, value_lr) = (self.config.policy_lr * lr, self.config.value_lr * lr);
+
+        let mut step = StepMetrics {
+            batch: self.batches,
+            epoch,
+            lr,
+            policy_loss: 0.0,
+            value_loss: 0.0,
+            policy_grad_norm: 0.0,
+            value_grad_norm: 0.0,
+            samples_per_sec: 0.0,
+            validation: None,
+        };
+        let (mut samples, mut policy_samples) = (0, 0);
+        // Scaling the step by the sample's weight scales its term in the loss.
+        let mut train = |i: &Sample| {
+            let (policy, value) = match policy_target {
+                PolicyTarget::Result => self.thod.train(&i.state, i.target, policy_lr * i.weight, value_lr * i.weight, weights),
+                PolicyTarget::Moves => (
+                    self.thod.train_moves(&i.board, &i.moves, policy_lr * i.weight * weights.policy),
+                    self.thod.train(&i.state, i.target, 0.0, value_lr * i.weight, weights).1,
+                ),
+            };
+            if policy_target == PolicyTarget::Result || !i.moves.is_empty() {
+                policy_samples += 1;
+            }
+            step.policy_loss += policy.loss;
+            step.value_loss += value.loss;
+            step.policy_grad_norm += policy.grad_norm;
+            step.value_grad_norm += value.grad_norm;
+            samples += 1;
+        };
+
+        for i in batch {
+            train(i);
+            if self.config.augment_flip {
+                train(&i.flipped());
             }
         }
-    
-        thod.save("test.json").unwrap();
-
-        let mut ploss = 0.0;
-        let mut vloss = 0.0;
-        for i in &test {
-            let (p0, p1) = i.winrate();
-            let pol = thod.policy(&i.state());
-            let val = thod.value(&i.state());
-            // println!("{pol} {val} {p0}");
-            ploss += Cost::CrossEntropy.apply(&arr1(&[pol, 1.0 - pol]), &arr1(&[p0, p1])).sum();
-            vloss += Cost::CrossEntropy.apply(&arr1(&[val, 1.0 - val]), &arr1(&[p0, p1])).sum();
+
+        let (n, policy_n) = (samples.max(1) as f32, policy_samples.max(1) as f32);
+        totals.batches += 1;
+        totals.samples += samples;
+        totals.policy_samples += policy_samples;
+        totals.policy_loss += step.policy_loss;
+        totals.value_loss += step.value_loss;
+
+        step.policy_loss /= policy_n;
+        step.value_loss /= n;
+        step.policy_grad_norm /= policy_n;
+        step.value_grad_norm /= n;
+        step.samples_per_sec = samples as f32 / start.elapsed().as_secs_f32().max(f32::EPSILON);
+
+        self.batches += 1;
+        if self.batches.is_multiple_of(self.config.validate_every.max(1)) {
+            self.thod.save(&self.config.model_out)?;
+            let validation = self.evaluate();
+            println!("Epoch {epoch} batch {} lr x{lr}", self.batches);
+            println!("Validation policy loss -> {}", validation.policy_loss);
+            println!("Validation value  loss -> {}", validation.value_loss);
+            step.validation = Some(validation);
         }
-        ploss /= 64.0;
-        vloss /= 64.0;
-        println!("Policy loss -> {ploss}");
-        println!("Value  loss -> {vloss}");
+
+        self.log.log(&Metric::Step(step))
     }
 
-    // println!("{:?}", batch);
+    fn finish_epoch(&mut self) -> Result<()> {
+        let Some(totals) = self.epoch.take() else { return Ok(()) };
+        let n = totals.samples.max(1) as f32;
+        let (policy_weight_norms, value_weight_norms) = self.thod.weight_norms();
+
+        self.log.log(&Metric::Epoch(EpochMetrics {
+            epoch: totals.epoch,
+            batches: totals.batches,
+            policy_loss: totals.policy_loss / totals.policy_samples.max(1) as f32,
+            value_loss: totals.value_loss / n,
+            validation: self.evaluate(),
+            policy_weight_norms,
+            value_weight_norms,
+            samples_per_sec: totals.samples as f32 / totals.start.elapsed().as_secs_f32().max(f32::EPSILON),
+        }))
+    }
+
+    fn evaluate(&self) -> Evaluation {
+        self.evaluate_on(&self.validation)
+    }
+
+    fn evaluate_on(&self, samples: &[Sample]) -> Evaluation {
+        let mut eval = Evaluation::default();
+        let mut total = 0.0;
+        let mut decisive = 0.0;
+        let (mut moves, mut move_total) = (MoveAccuracy::default(), 0.0);
+        for i in samples {
+            let (p0, p1) = (i.target, 1.0 - i.target);
+            let (pol, val) = self.thod.predict(&i.state);
+            match self.policy_target() {
+                PolicyTarget::Result => eval.policy_loss += i.weight * Cost::CrossEntropy.apply(&arr1(&[pol, 1.0 - pol]), &arr1(&[p0, p1])).sum(),
+                PolicyTarget::Moves => if let Some(x) = self.thod.predict_moves(&i.board, &i.moves) {
+                    eval.policy_loss += i.weight * x.loss;
+                    moves.top1 += i.weight * (x.rank < 1) as u8 as f32;
+                    moves.top3 += i.weight * (x.rank < 3) as u8 as f32;
+                    move_total += i.weight;
+                },
+            }
+            eval.value_loss += i.weight * Cost::CrossEntropy.apply(&arr1(&[val, 1.0 - val]), &arr1(&[p0, p1])).sum();
+            total += i.weight;
+
+            // Positions the target calls even have no winner to predict.
+            if p0 != 0.5 {
+                eval.policy_accuracy += i.weight * ((pol > 0.5) == (p0 > 0.5)) as u8 as f32;
+                eval.value_accuracy += i.weight * ((val > 0.5) == (p0 > 0.5)) as u8 as f32;
+                decisive += i.weight;
+            }
+        }
+
+        match self.policy_target() {
+            PolicyTarget::Result => eval.policy_loss /= total.max(f32::EPSILON),
+            PolicyTarget::Moves => {
+                eval.policy_loss /= move_total.max(f32::EPSILON);
+                eval.moves = (move_total > 0.0).then(|| MoveAccuracy {
+                    top1: moves.top1 / move_total,
+                    top3: moves.top3 / move_total,
+                });
+            },
+        }
+        eval.value_loss /= total.max(f32::EPSILON);
+        eval.policy_accuracy /= decisive.max(f32::EPSILON);
+        eval.value_accuracy /= decisive.max(f32::EPSILON);
+        eval
+    }
+
+    /// Up to `validation_size` positions from a held-out `split` of the database.
+    fn held_out_from_database(&mut self, conn: &Connection, split: Split) -> Result<Vec<Sample>> {
+        let config = &self.config;
+        let mut sampler = EpochSampler::new(conn, split, config.validation_min_occurences, self.rng.gen())?;
+        report_skipped(sampler.next_batch(conn, config.validation_size)?)
+            .instances
+            .iter()
+            .map(|i| Ok(Sample::from_instance(i, i.state(), self.moves(conn, &i.board)?, &config.smoothing)))
+            .collect()
+    }
+
+    /// Up to `validation_size` records whose hash puts them in a held-out `split`.
+    fn held_out_from_records(&self, path: &str, split: Split) -> Result<Vec<Sample>> {
+        PackedReader::open(path)?
+            .filter(in_split(split))
+            .take(self.config.validation_size)
+            .map(|x| x.map(Sample::from_record))
+            .collect()
+    }
+
+    /// Evaluates the final network on the test split, which nothing during training looks at.
+    fn test(&mut self) -> Result<()> {
+        let samples = match self.config.records.clone() {
+            Some(path) => self.held_out_from_records(&path, Split::Test)?,
+            None => self.held_out_from_database(&init(&self.config.database)?, Split::Test)?,
+        };
+        if samples.is_empty() {
+            println!("No test positions to evaluate");
+            return Ok(());
+        }
+
+        let test = self.evaluate_on(&samples);
+        println!("Test policy loss -> {} over {} positions", test.policy_loss, samples.len());
+        println!("Test value  loss -> {}", test.value_loss);
+        self.log.log(&Metric::Test(test))
+    }
+
+    fn train_from_database(&mut self) -> Result<()> {
+        let config = self.config.clone();
+        let conn = init(&config.database)?;
+        self.validation = self.held_out_from_database(&conn, Split::Validation)?;
+
+        let mut epochs_done = 0;
+        for (n, phase) in config.phases.iter().enumerate() {
+            println!("Phase {n}: positions seen more than {} times", phase.min_occurences);
+
+            let reader = init(&config.database)?;
+            let sampler = EpochSampler::new(&reader, Split::Train, phase.min_occurences, self.rng.gen())?;
+            let moves = self.policy_target() == PolicyTarget::Moves;
+            for batch in Prefetcher::spawn(reader, sampler, config.batch_size, PREFETCH_BATCHES, Some(phase.epochs), moves) {
+                let batch = batch?;
+                let samples = report_skipped(batch.batch).instances.iter()
+                    .zip(batch.states)
+                    .zip(batch.moves)
+                    .map(|((i, state), moves)| Sample::from_instance(i, state, moves, &config.smoothing))
+                    .collect::<Vec<_>>();
+
+                self.step(&samples, epochs_done + batch.epoch)?;
+            }
+            epochs_done += phase.epochs;
+        }
+
+        Ok(())
+    }
+
+    /// Streams a packed record file without touching the database, holding out the positions whose hash puts
+    /// them in the validation split.
+    fn train_from_records(&mut self, path: &str) -> Result<()> {
+        self.validation = self.held_out_from_records(path, Split::Validation)?;
+
+        for epoch in 0..self.config.epochs() {
+            let mut records = PackedReader::open(path)?
+                .filter(in_split(Split::Train))
+                .map(|x| x.map(Sample::from_record))
+                .peekable();
+
+            while records.peek().is_some() {
+                let batch = records.by_ref().take(self.config.batch_size).collect::<Result<Vec<_>>>()?;
+                self.step(&batch, epoch)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Keeps records in `split`, and errors so they aren't lost.
+fn in_split(split: Split) -> impl Fn(&Result<PackedRecord>) -> bool {
+    move |x| x.as_ref().map_or(true, |x| Split::from_hash(x.state.board.hash() as i64) == split)
+}
+
+/// Comma separated sizes, or `none` for no hidden layers.
+fn parse_layers(value: &str) -> Result<Vec<usize>> {
+    if value == "none" { return Ok(vec![]) }
+    Ok(value.split(',').map(|x| x.trim().parse()).collect::<Result<Vec<_>, _>>()?)
+}
+
+fn parse_phase(value: &str) -> Result<Phase> {
+    let (min, epochs) = value.split_once(':').ok_or_else(|| anyhow!("Expected MIN:EPOCHS, got `{value}`"))?;
+    Ok(Phase { min_occurences: min.parse()?, epochs: epochs.parse()? })
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<TrainConfig>> {
+    let mut args = args.into_iter();
+    let mut config = TrainConfig::default();
+    let (mut phases, mut epochs) = (None, None);
+    let mut resume = false;
+    let (mut lr_step, mut lr_gamma) = (None, None);
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--config" => config = TrainConfig::load(&value()?)?,
+            "--database" => config.database = value()?,
+            "--records" => config.records = Some(value()?),
+            "--model-in" => config.model_in = Some(value()?),
+            "--model-out" => config.model_out = value()?,
+            "--resume" => resume = true,
+            "--metrics" => config.metrics = Some(value()?),
+            "--trunk-layers" => config.trunk_layers = parse_layers(&value()?)?,
+            "--policy-layers" => config.policy_layers = parse_layers(&value()?)?,
+            "--value-layers" => config.value_layers = parse_layers(&value()?)?,
+            "--init" => config.init.scheme = value()?.parse()?,
+            "--zero-biases" => config.init.zero_biases = true,
+            "--phase" => phases.get_or_insert_with(Vec::new).push(parse_phase(&value()?)?),
+            "--epochs" => epochs = Some(value()?.parse()?),
+            "--batch-size" => config.batch_size = value()?.parse()?,
+            "--policy-lr" => config.policy_lr = value()?.parse()?,
+            "--value-lr" => config.value_lr = value()?.parse()?,
+            "--policy-weight" => config.loss_weights.policy = value()?.parse()?,
+            "--value-weight" => config.loss_weights.value = value()?.parse()?,
+            "--policy-target" => config.policy_target = match value()?.as_str() {
+                "moves" => PolicyTarget::Moves,
+                "result" => PolicyTarget::Result,
+                x => bail!("Unknown policy target `{x}`, expected moves or result"),
+            },
+            "--lr-step" => lr_step = Some(value()?.parse()?),
+            "--lr-gamma" => lr_gamma = Some(value()?.parse()?),
+            "--seed" => config.seed = value()?.parse()?,
+            "--validate-every" => config.validate_every = value()?.parse()?,
+            "--validation-size" => config.validation_size = value()?.parse()?,
+            "--validation-min" => config.validation_min_occurences = value()?.parse()?,
+            "--no-flip" => config.augment_flip = false,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(None);
+            },
+            _ => bail!("Unknown option `{arg}`\n\n{USAGE}"),
+        }
+    }
+
+    if let Some(phases) = phases {
+        config.phases = phases;
+    }
+    if resume && Path::new(&config.model_out).exists() {
+        if config.model_in.is_some() { bail!("--resume and --model-in both choose the starting network") }
+        config.model_in = Some(config.model_out.clone());
+    }
+    if let Some(epochs) = epochs {
+        match config.phases.last_mut() {
+            Some(x) => x.epochs = epochs,
+            None => bail!("--epochs needs at least one phase"),
+        }
+    }
+    if lr_step.is_some() || lr_gamma.is_some() {
+        config.lr_schedule = LrSchedule::Step { every: lr_step.unwrap_or(1000), gamma: lr_gamma.unwrap_or(0.5) };
+    }
+    if config.batch_size == 0 { bail!("--batch-size must be positive") }
+
+    Ok(Some(config))
+}
+
+fn run() -> Result<()> {
+    let Some(config) = parse_args(env::args().skip(1))? else { return Ok(()) };
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    if config.model_in.is_none() && Path::new(&config.model_out).exists() {
+        println!("Starting a new network over {}; pass --resume to continue from it", config.model_out);
+    }
+    let thod = match &config.model_in {
+        Some(path) => Thod::from_file(path)?,
+        None => Thod::with_trunk(
+            config.trunk_layers.clone(), config.policy_layers.clone(), config.value_layers.clone(), config.init, &mut rng,
+        ),
+    };
+
+    config.save(&config.saved_path())?;
+    println!("Saved run config to {}, logging metrics to {}", config.saved_path(), config.metrics_path());
+
+    let records = config.records.clone();
+    let log = MetricsLog::open(&config.metrics_path())?;
+    let mut trainer = Trainer { thod, config, validation: vec![], batches: 0, log, epoch: None, rng };
+    match records {
+        Some(path) => trainer.train_from_records(&path)?,
+        None => trainer.train_from_database()?,
+    }
+    trainer.finish_epoch()?;
+    trainer.test()?;
+
+    trainer.thod.save(&trainer.config.model_out)
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> TrainConfig {
+        parse_args(args.split_whitespace().map(String::from)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn epochs_apply_to_the_last_phase() {
+        let config = parse("--epochs 5 --phase 10:1 --phase 0:3");
+        assert_eq!(config.epochs(), 6);
+        assert_eq!(config.phases[1].epochs, 5);
+
+        assert_eq!(parse("--epochs 4").phases.last().unwrap().epochs, 4);
+    }
+
+    #[test]
+    fn held_out_threshold_is_configurable() {
+        assert_eq!(parse("").validation_min_occurences, 5);
+        assert_eq!(parse("--validation-min 0").validation_min_occurences, 0);
+    }
+
+    #[test]
+    fn resume_is_recorded_as_model_in() {
+        let path = env::temp_dir().join(format!("chester-resume-{}.json", std::process::id()));
+        let out = path.to_string_lossy();
+        assert_eq!(parse(&format!("--model-out {out} --resume")).model_in, None);
+
+        std::fs::write(&path, "{}").unwrap();
+        assert_eq!(parse(&format!("--model-out {out}")).model_in, None);
+        assert_eq!(parse(&format!("--resume --model-out {out}")).model_in.as_deref(), Some(&*out));
+        std::fs::remove_file(&path).unwrap();
+    }
 }
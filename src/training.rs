@@ -0,0 +1,145 @@
+use std::{fs::File, io::{Read, Write}, path::Path};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{database::TargetSmoothing, engine::ai::LossWeights, neural_net::Initializer};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Phase {
+    /// Only positions seen more than this many times are trained on.
+    pub min_occurences: usize,
+    /// Passes over the training split.
+    pub epochs: usize,
+}
+
+/// Multiplier applied to both learning rates as training goes on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LrSchedule {
+    Constant,
+    /// Multiply by `gamma` every `every` batches.
+    Step { every: usize, gamma: f32 },
+}
+
+impl LrSchedule {
+    pub fn factor(&self, batch: usize) -> f32 {
+        match self {
+            Self::Constant => 1.0,
+            Self::Step { every, gamma } => gamma.powi((batch / (*every).max(1)) as i32),
+        }
+    }
+}
+
+/// What the policy head is trained towards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyTarget {
+    /// White's win probability, the same target as the value head.
+    Result,
+    /// The distribution of moves played from the position in the database's games.
+    #[default]
+    Moves,
+}
+
+/// Everything needed to reproduce a training run. Saved as JSON next to the model it produces.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrainConfig {
+    pub database: String,
+    /// Train from a packed record file instead of the database. Records have no counts, so phases only
+    /// contribute their epochs.
+    pub records: Option<String>,
+    /// Network to start from. When unset, a new network with the layer sizes below is made.
+    pub model_in: Option<String>,
+    pub model_out: String,
+    /// JSON-lines metrics log, kept next to `model_out` when unset.
+    pub metrics: Option<String>,
+    /// Layer sizes of a shared trunk for a new network, whose heads then get the policy and value layers
+    /// below. Empty for two separate towers.
+    pub trunk_layers: Vec<usize>,
+    pub policy_layers: Vec<usize>,
+    pub value_layers: Vec<usize>,
+    /// Initialisation of a new network; ignored when continuing from a saved one.
+    pub init: Initializer,
+    pub phases: Vec<Phase>,
+    pub batch_size: usize,
+    pub policy_lr: f32,
+    pub value_lr: f32,
+    pub loss_weights: LossWeights,
+    /// Records have no moves, so with `records` the policy always trains on results.
+    pub policy_target: PolicyTarget,
+    pub lr_schedule: LrSchedule,
+    /// Seeds network initialisation and sampling; the same seed and data give the same network.
+    pub seed: u64,
+    /// Batches between validation reports and checkpoints.
+    pub validate_every: usize,
+    /// Positions in each held-out set: the validation set reported during training and the test set reported
+    /// once at the end.
+    pub validation_size: usize,
+    /// Held-out positions must have been seen more than this many times, so their targets are worth scoring.
+    pub validation_min_occurences: usize,
+    /// Also train on every position with colours flipped, so both sides are learnt the same way.
+    pub augment_flip: bool,
+    pub smoothing: TargetSmoothing,
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        Self {
+            database: "chess.db".into(),
+            records: None,
+            model_in: None,
+            model_out: "test.json".into(),
+            metrics: None,
+            trunk_layers: vec![],
+            policy_layers: vec![500, 250, 100],
+            value_layers: vec![750, 500, 250, 100, 10],
+            init: Initializer::default(),
+            // Starts on well-established positions before widening to rarely seen ones.
+            phases: vec![
+                Phase { min_occurences: 20, epochs: 1 },
+                Phase { min_occurences: 5, epochs: 2 },
+                Phase { min_occurences: 0, epochs: 10 },
+            ],
+            batch_size: 10,
+            policy_lr: 0.02,
+            value_lr: 0.01,
+            loss_weights: LossWeights::default(),
+            policy_target: PolicyTarget::default(),
+            lr_schedule: LrSchedule::Constant,
+            seed: 0,
+            validate_every: 1,
+            validation_size: 64,
+            validation_min_occurences: 5,
+            augment_flip: true,
+            smoothing: TargetSmoothing::default(),
+        }
+    }
+}
+
+impl TrainConfig {
+    pub fn load(path: &str) -> Result<Self> {
+        let mut buf = String::new();
+        File::open(path)?.read_to_string(&mut buf)?;
+        Ok(serde_json::from_str(&buf)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        File::create(path)?.write_all(&serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Where the config of the run writing `model_out` is kept, e.g. `run/model.config.json` for `run/model.json`.
+    pub fn saved_path(&self) -> String {
+        Path::new(&self.model_out).with_extension("config.json").to_string_lossy().into_owned()
+    }
+
+    pub fn metrics_path(&self) -> String {
+        self.metrics.clone()
+            .unwrap_or_else(|| Path::new(&self.model_out).with_extension("metrics.jsonl").to_string_lossy().into_owned())
+    }
+
+    pub fn epochs(&self) -> usize {
+        self.phases.iter().map(|x| x.epochs).sum()
+    }
+}